@@ -0,0 +1,96 @@
+// Measures the cost of one server tick at different player counts
+// Run with `cargo bench`
+//
+// "engine" is PhysicsEngine::tick: the physics step plus building the shared world snapshot
+// "views" is the work every connection does with it: deriving its own snapshot and delta encoding it
+use actix::dev::channel::channel;
+use actix::Addr;
+use multiplayer_shooter::custom_ws::Ws;
+use multiplayer_shooter::physics_engine::{Coords, PhysicsEngine, PlayerInput};
+use multiplayer_shooter::protocol::{Snapshot, SnapshotEncoder};
+use multiplayer_shooter::state::{InnerState, Settings};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WARMUP_TICKS: u32 = 256;
+const MEASURED_TICKS: u32 = 512;
+
+fn main() {
+    for players in [10, 50, 200] {
+        let (engine, views) = bench(players);
+        println!(
+            "{:>3} players: engine {:>10.2?}/tick  views {:>10.2?}/tick  total {:>10.2?}/tick",
+            players,
+            engine,
+            views,
+            engine + views
+        );
+    }
+}
+
+fn bench(players: usize) -> (Duration, Duration) {
+    // Nobody may die, or later ticks would be measured with fewer players
+    let mut settings = Settings::load();
+    for rule in settings.collision_rules.iter_mut() {
+        rule.damage = 0.0;
+    }
+    let state = InnerState::with_settings(settings);
+    let mut engine = PhysicsEngine::new(Arc::new(state));
+
+    // Addresses whose receivers are dropped straight away, so the engine's messages go nowhere
+    let addresses: Vec<Addr<Ws>> = (0..players)
+        .map(|_| Addr::new(channel::<Ws>(16).0))
+        .collect();
+    for (i, address) in addresses.iter().enumerate() {
+        engine.join_game(address.clone(), format!("player {}", i));
+    }
+
+    let mut encoders: Vec<_> = (0..players).map(|_| SnapshotEncoder::new()).collect();
+    let camera = Coords { x: 0.0, y: 0.0 };
+    let (mut engine_time, mut views_time) = (Duration::ZERO, Duration::ZERO);
+
+    for tick in 0..WARMUP_TICKS + MEASURED_TICKS {
+        // Everyone runs around in circles and shoots
+        for (i, address) in addresses.iter().enumerate() {
+            let phase = (tick as usize / 64 + i) % 4;
+            engine.set_input(
+                address,
+                PlayerInput {
+                    w: phase == 0,
+                    a: phase == 1,
+                    s: phase == 2,
+                    d: phase == 3,
+                    dir: i as f32,
+                    click: true,
+                    seq: tick + 1,
+                },
+            );
+        }
+
+        let start = Instant::now();
+        engine.tick();
+        let engine_elapsed = start.elapsed();
+
+        // Worst case for the views: everyone can see everything
+        let world = engine.world_snapshot();
+        let start = Instant::now();
+        for (player, encoder) in world.players.iter().zip(encoders.iter_mut()) {
+            let snapshot = Snapshot {
+                seq: encoder.next_seq(),
+                ..Snapshot::view(&world, Some(player.id), None, camera)
+            };
+            let seq = snapshot.seq;
+            std::hint::black_box(encoder.encode(snapshot));
+            encoder.ack(seq);
+        }
+        let views_elapsed = start.elapsed();
+        assert_eq!(world.players.len(), players);
+
+        if tick >= WARMUP_TICKS {
+            engine_time += engine_elapsed;
+            views_time += views_elapsed;
+        }
+    }
+
+    (engine_time / MEASURED_TICKS, views_time / MEASURED_TICKS)
+}
@@ -0,0 +1,179 @@
+use crate::physics_engine::EntityId;
+use std::collections::HashMap;
+use std::time::Instant;
+
+pub type TeamId = u32;
+
+// Game state of a single entity that the physics engine doesn't know about
+#[derive(Debug, Clone, PartialEq)]
+pub struct Components {
+    pub health: f32,
+    pub max_health: f32,
+    // Fraction of incoming damage absorbed, from 0 to 1
+    pub armor: f32,
+    pub team: Option<TeamId>,
+    // Entity that created this one, the shooter for bullets
+    pub owner: Option<EntityId>,
+    // Spawn protection, damage is ignored until then
+    pub protected_until: Option<Instant>,
+}
+
+impl Components {
+    pub fn player(max_health: f32, armor: f32) -> Self {
+        Components {
+            health: max_health,
+            max_health,
+            armor,
+            team: None,
+            owner: None,
+            protected_until: None,
+        }
+    }
+
+    pub fn bullet(owner: EntityId) -> Self {
+        Components {
+            health: 0.0,
+            max_health: 0.0,
+            armor: 0.0,
+            team: None,
+            owner: Some(owner),
+            protected_until: None,
+        }
+    }
+
+    // Takes damage after armor, never going below 0 health
+    // Returns the health actually lost
+    pub fn damage(&mut self, amount: f32) -> f32 {
+        let amount = amount.max(0.0) * (1.0 - self.armor.clamp(0.0, 1.0));
+        let lost = amount.min(self.health);
+        self.health -= lost;
+        lost
+    }
+
+    pub fn is_protected(&self, now: Instant) -> bool {
+        self.protected_until.is_some_and(|until| now < until)
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.health <= 0.0
+    }
+
+    // Health from 0 to 1, as sent to clients
+    pub fn health_fraction(&self) -> f32 {
+        if self.max_health <= 0.0 {
+            return 0.0;
+        }
+        (self.health / self.max_health).clamp(0.0, 1.0)
+    }
+}
+
+// Components of every entity, kept next to the physics bodies
+#[derive(Debug, Default)]
+pub struct ComponentStore {
+    entities: HashMap<EntityId, Components>,
+}
+
+impl ComponentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: EntityId, components: Components) {
+        self.entities.insert(id, components);
+    }
+
+    pub fn remove(&mut self, id: EntityId) -> Option<Components> {
+        self.entities.remove(&id)
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&Components> {
+        self.entities.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut Components> {
+        self.entities.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &Components)> {
+        self.entities.iter().map(|(&id, c)| (id, c))
+    }
+
+    pub fn owner(&self, id: EntityId) -> Option<EntityId> {
+        self.get(id).and_then(|c| c.owner)
+    }
+
+    pub fn team(&self, id: EntityId) -> Option<TeamId> {
+        self.get(id).and_then(|c| c.team)
+    }
+
+    // Entities that are out of health
+    pub fn dead(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.iter()
+            .filter(|(_, c)| c.max_health > 0.0 && c.is_dead())
+            .map(|(id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damage_reduces_health() {
+        let mut c = Components::player(100.0, 0.0);
+        assert_eq!(c.damage(30.0), 30.0);
+        assert_eq!(c.health, 70.0);
+        assert!(!c.is_dead());
+        assert_eq!(c.health_fraction(), 0.7);
+    }
+
+    #[test]
+    fn damage_stops_at_zero() {
+        let mut c = Components::player(100.0, 0.0);
+        c.damage(80.0);
+        assert_eq!(c.damage(50.0), 20.0);
+        assert_eq!(c.health, 0.0);
+        assert!(c.is_dead());
+        assert_eq!(c.health_fraction(), 0.0);
+    }
+
+    #[test]
+    fn armor_absorbs_a_fraction() {
+        let mut c = Components::player(100.0, 0.25);
+        assert_eq!(c.damage(40.0), 30.0);
+        assert_eq!(c.health, 70.0);
+    }
+
+    #[test]
+    fn armor_and_damage_are_clamped() {
+        let mut c = Components::player(100.0, 1.5);
+        assert_eq!(c.damage(40.0), 0.0);
+        c.armor = -1.0;
+        assert_eq!(c.damage(40.0), 40.0);
+        assert_eq!(c.damage(-10.0), 0.0);
+        assert_eq!(c.health, 60.0);
+    }
+
+    #[test]
+    fn protection_expires() {
+        let now = Instant::now();
+        let mut c = Components::player(100.0, 0.0);
+        assert!(!c.is_protected(now));
+        c.protected_until = Some(now + std::time::Duration::from_secs(1));
+        assert!(c.is_protected(now));
+        assert!(!c.is_protected(now + std::time::Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn bullets_are_never_dead() {
+        let mut store = ComponentStore::new();
+        store.insert(1, Components::player(100.0, 0.0));
+        store.insert(2, Components::bullet(1));
+        assert_eq!(store.owner(2), Some(1));
+        assert_eq!(store.dead().count(), 0);
+
+        store.get_mut(1).unwrap().damage(100.0);
+        assert_eq!(store.dead().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(store.get(2).unwrap().health_fraction(), 0.0);
+    }
+}
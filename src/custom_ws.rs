@@ -1,18 +1,111 @@
-use crate::physics_engine::{self, Coords};
-use crate::physics_engine::{GameOver, PhysicsStateResponse};
-use crate::state::State;
-use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, StreamHandler};
+use crate::map::MapEdit;
+use crate::physics_engine::{
+    ArenaChanged, EditRejected, GameEvents, GameOver, KnownEntities, MapEdited, MapSaved,
+    PhysicsStateResponse, Respawned, TICK_RATE,
+};
+use crate::protocol::{
+    negotiate_encoding, negotiate_version, Encoding, ErrorCode, ServerMessage, Snapshot,
+    SnapshotEncoder,
+};
+use crate::state::{Phase, State};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, Running, StreamHandler};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
-use actix_web_actors;
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 /// Define HTTP actor
 pub struct Ws {
     state: web::Data<State>,
     start_timestamp: Instant,
+    snapshot_encoder: SnapshotEncoder,
+    // Negotiated in the Hello handshake, None until then
+    protocol_version: Option<u32>,
+    encoding: Option<Encoding>,
+    // Mirrored into PlayerInfo so that everyone else can see it
+    phase: Phase,
+    // Number of invalid messages received, see Settings::max_strikes
+    strikes: u32,
+    // When we last received anything at all from the client, see Settings::idle_timeout_ms
+    last_heard: Instant,
+    // Round trip time measured by the last answered ping
+    rtt: Option<Duration>,
+    // Allowed to edit the map, see ClientInstruction::Authenticate
+    admin: bool,
+    // Earliest the player may come back after dying, from GameOver
+    // Spectating in between doesn't shorten the countdown
+    respawn_at: Option<Instant>,
+}
+
+impl Ws {
+    // Handles the Hello handshake, which must be the first message on a connection
+    fn hello(&mut self, version: u32, encodings: Vec<String>, ctx: &mut <Self as Actor>::Context) {
+        let negotiated = negotiate_version(version)
+            .and_then(|version| Ok((version, negotiate_encoding(&encodings)?)));
+        match negotiated {
+            Ok((version, encoding)) => {
+                self.protocol_version = Some(version);
+                self.encoding = Some(encoding);
+                self.snapshot_encoder = SnapshotEncoder::with_version(version);
+                let arena = self.state.arena();
+                let welcome = ServerMessage::Welcome {
+                    version,
+                    encoding,
+                    tick_rate: TICK_RATE,
+                    settings: &arena.settings,
+                    map: &arena.map,
+                };
+                self.send(&welcome, ctx);
+            }
+            Err(reason) => self.refuse(reason, ctx),
+        }
+    }
+
+    // Sends a text message to the client
+    fn send(&self, msg: &ServerMessage, ctx: &mut <Self as Actor>::Context) {
+        ctx.text(serde_json::to_string(msg).unwrap());
+    }
+
+    // Moves the connection to a new phase, or returns why it can't move there
+    fn transition(&mut self, to: Phase, ctx: &mut <Self as Actor>::Context) -> Result<(), String> {
+        self.phase = self.phase.transition(to)?;
+        if let Some(mut player_info) = self.state.connected_players.get_mut(&ctx.address()) {
+            player_info.phase = self.phase;
+        }
+        if self.phase != Phase::Leaving {
+            self.send(&ServerMessage::Phase { phase: self.phase }, ctx);
+        }
+        Ok(())
+    }
+
+    // Tells the client what was wrong with its message and counts a strike against it
+    // Too many strikes and the connection is closed
+    fn reject(&mut self, code: ErrorCode, message: String, ctx: &mut <Self as Actor>::Context) {
+        self.send(&ServerMessage::Error { code, message }, ctx);
+        self.strikes += 1;
+        if self.strikes >= self.state.settings.max_strikes {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(format!("too many invalid messages ({})", self.strikes)),
+            }));
+            ctx.stop();
+        }
+    }
+
+    // Tells the client why it can't play and closes the connection
+    fn refuse(&mut self, reason: String, ctx: &mut <Self as Actor>::Context) {
+        self.send(
+            &ServerMessage::Refused {
+                reason: reason.clone(),
+            },
+            ctx,
+        );
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(reason),
+        }));
+        ctx.stop();
+    }
 }
 
 impl Actor for Ws {
@@ -20,6 +113,29 @@ impl Actor for Ws {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.state.register(ctx.address());
+
+        // Ping the client regularly to measure RTT, and drop it once it has gone quiet
+        let heartbeat = Duration::from_millis(self.state.settings.heartbeat_interval_ms);
+        ctx.run_interval(heartbeat, |s, ctx| {
+            let idle_timeout = Duration::from_millis(s.state.settings.idle_timeout_ms);
+            if s.last_heard.elapsed() > idle_timeout {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Away,
+                    description: Some(String::from("idle timeout")),
+                }));
+                ctx.stop();
+                return;
+            }
+            // The pong echoes the payload back, so the payload is when we sent it
+            let sent_at = s.start_timestamp.elapsed().as_millis() as u64;
+            ctx.ping(&sent_at.to_le_bytes());
+        });
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        // Every phase may move to Leaving, and Leaving to Leaving is the only refusal
+        let _ = self.transition(Phase::Leaving, ctx);
+        Running::Stop
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
@@ -35,9 +151,19 @@ impl Actor for Ws {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum ClientInstruction {
+    // First message on every connection
+    // encodings are listed in order of preference, see protocol::Encoding
+    Hello {
+        version: u32,
+        encodings: Vec<String>,
+    },
     JoinGame {
         username: String,
     },
+    // Watch the game without a body
+    Spectate,
+    // Come back after dying, as soon as the respawn countdown allows
+    Respawn,
     GameAction {
         w: bool,
         a: bool,
@@ -45,13 +171,43 @@ pub enum ClientInstruction {
         d: bool,
         dir: f32,
         click: bool,
+        // Increases with every GameAction, starting from 1
+        seq: u32,
+    },
+    // Size of the client's screen, only entities inside it (plus a margin) are sent
+    Viewport {
+        width: f32,
+        height: f32,
+    },
+    // Client has received snapshot seq and will use it as the baseline for deltas
+    Ack {
+        seq: u32,
+    },
+    // Client lost its baseline and needs a full snapshot
+    RequestKeyframe,
+    // Grants admin rights for the rest of the connection, see InnerState::admin_token
+    Authenticate {
+        token: String,
+    },
+    // Admins only
+    EditMap {
+        edit: MapEdit,
     },
 }
 
 #[derive(Debug)]
 pub enum GameInstruction {
-    JoinGame,
+    JoinGame {
+        username: String,
+    },
+    Spectate,
+    Respawn,
+    Viewport {
+        width: f32,
+        height: f32,
+    },
     ExitGame,
+    EditMap(MapEdit),
     GameAction {
         w: bool,
         a: bool,
@@ -59,6 +215,7 @@ pub enum GameInstruction {
         d: bool,
         dir: f32,
         click: bool,
+        seq: u32,
     },
 }
 
@@ -73,11 +230,37 @@ pub struct PhysicsInstruction {
 /// Processes requests to Physics Engine
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Ws {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if msg.is_ok() {
+            self.last_heard = Instant::now();
+        }
         match msg {
             Ok(ws::Message::Text(text)) => {
                 // Parse JSON from client
-                // TODO: Error handle this properly
-                let mut action: ClientInstruction = serde_json::from_slice(text.as_ref()).unwrap();
+                let mut action: ClientInstruction = match serde_json::from_slice(text.as_ref()) {
+                    Ok(action) => action,
+                    Err(e) => {
+                        self.reject(ErrorCode::MalformedMessage, e.to_string(), ctx);
+                        return;
+                    }
+                };
+
+                // Nothing but the handshake is accepted until a version has been agreed on
+                match (&action, self.protocol_version) {
+                    (ClientInstruction::Hello { .. }, Some(_)) => {
+                        self.reject(
+                            ErrorCode::DuplicateHello,
+                            String::from("handshake already completed"),
+                            ctx,
+                        );
+                        return;
+                    }
+                    (ClientInstruction::Hello { .. }, None) => (),
+                    (_, Some(_)) => (),
+                    (_, None) => {
+                        self.refuse(String::from("expected Hello as the first message"), ctx);
+                        return;
+                    }
+                }
 
                 // If the name is blank then change name to 'Unnamed'
                 if let ClientInstruction::JoinGame { username: s } = &mut action {
@@ -87,15 +270,80 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Ws {
                 }
 
                 let action = match action {
+                    ClientInstruction::Hello { version, encodings } => {
+                        self.hello(version, encodings, ctx);
+                        return;
+                    }
+                    // Dead players come back with Respawn, once their countdown is over
+                    ClientInstruction::JoinGame { .. } if self.phase == Phase::Dead => {
+                        self.reject(
+                            ErrorCode::InvalidTransition,
+                            String::from("JoinGame sent while Dead, use Respawn"),
+                            ctx,
+                        );
+                        return;
+                    }
+                    ClientInstruction::JoinGame { .. }
+                        if self.respawn_at.is_some_and(|at| Instant::now() < at) =>
+                    {
+                        self.reject(
+                            ErrorCode::InvalidTransition,
+                            String::from("JoinGame sent before the respawn countdown is over"),
+                            ctx,
+                        );
+                        return;
+                    }
                     ClientInstruction::JoinGame { username } => {
+                        if let Err(reason) = self.transition(Phase::Joined, ctx) {
+                            self.reject(ErrorCode::InvalidTransition, reason, ctx);
+                            return;
+                        }
                         // Now save our username
-                        let mut player_info = self
-                            .state
-                            .connected_players
-                            .get_mut(&ctx.address())
-                            .unwrap(); // Move the username out and construct new game instruction
-                        player_info.username = username;
-                        GameInstruction::JoinGame
+                        if let Some(mut player_info) =
+                            self.state.connected_players.get_mut(&ctx.address())
+                        {
+                            player_info.username = username.clone();
+                        }
+                        // Joining always starts from a full snapshot
+                        self.snapshot_encoder.request_keyframe();
+                        GameInstruction::JoinGame { username }
+                    }
+                    ClientInstruction::Spectate => {
+                        if let Err(reason) = self.transition(Phase::Spectating, ctx) {
+                            self.reject(ErrorCode::InvalidTransition, reason, ctx);
+                            return;
+                        }
+                        self.snapshot_encoder.request_keyframe();
+                        GameInstruction::Spectate
+                    }
+                    // The phase changes once the physics engine has respawned the player
+                    ClientInstruction::Respawn if self.phase != Phase::Dead => {
+                        self.reject(
+                            ErrorCode::InvalidTransition,
+                            format!("Respawn sent while {:?}", self.phase),
+                            ctx,
+                        );
+                        return;
+                    }
+                    ClientInstruction::Respawn => GameInstruction::Respawn,
+                    // Inputs that were already on their way when the player died
+                    ClientInstruction::GameAction { .. } if self.phase == Phase::Dead => return,
+                    ClientInstruction::GameAction { .. } if self.phase != Phase::Joined => {
+                        self.reject(
+                            ErrorCode::NotJoined,
+                            format!("GameAction sent while {:?}", self.phase),
+                            ctx,
+                        );
+                        return;
+                    }
+                    // An infinite or NaN direction would put a bullet nowhere and break the physics
+                    ClientInstruction::GameAction { dir, .. } if !dir.is_finite() => {
+                        self.reject(
+                            ErrorCode::MalformedMessage,
+                            format!("GameAction dir must be a finite number, got {}", dir),
+                            ctx,
+                        );
+                        return;
                     }
                     ClientInstruction::GameAction {
                         w,
@@ -104,6 +352,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Ws {
                         d,
                         dir,
                         click,
+                        seq,
                     } => GameInstruction::GameAction {
                         w,
                         a,
@@ -111,7 +360,43 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Ws {
                         d,
                         click,
                         dir,
+                        seq,
                     },
+                    ClientInstruction::Viewport { width, height } => {
+                        GameInstruction::Viewport { width, height }
+                    }
+                    // Snapshot bookkeeping is per connection, the physics engine never sees it
+                    ClientInstruction::Ack { seq } => {
+                        self.snapshot_encoder.ack(seq);
+                        return;
+                    }
+                    ClientInstruction::RequestKeyframe => {
+                        self.snapshot_encoder.request_keyframe();
+                        return;
+                    }
+                    ClientInstruction::Authenticate { token } => {
+                        match &self.state.admin_token {
+                            Some(admin_token) if *admin_token == token => {
+                                self.admin = true;
+                                self.send(&ServerMessage::Authenticated, ctx);
+                            }
+                            _ => self.reject(
+                                ErrorCode::Unauthorized,
+                                String::from("wrong admin token"),
+                                ctx,
+                            ),
+                        }
+                        return;
+                    }
+                    ClientInstruction::EditMap { .. } if !self.admin => {
+                        self.reject(
+                            ErrorCode::Unauthorized,
+                            String::from("EditMap sent without authenticating"),
+                            ctx,
+                        );
+                        return;
+                    }
+                    ClientInstruction::EditMap { edit } => GameInstruction::EditMap(edit),
                 };
 
                 // Wrap instruction with our Actor Address (so that the physics engine can remember who's who)
@@ -126,71 +411,174 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Ws {
                     .get_physics_engine()
                     .do_send(physics_instruction);
             }
-            _ => (),
+            Ok(ws::Message::Binary(_)) => self.reject(
+                ErrorCode::UnexpectedBinary,
+                String::from("client messages must be JSON text"),
+                ctx,
+            ),
+            Ok(ws::Message::Ping(payload)) => ctx.pong(&payload),
+            Ok(ws::Message::Pong(payload)) => {
+                if let Ok(sent_at) = <[u8; 8]>::try_from(payload.as_ref()) {
+                    let now = self.start_timestamp.elapsed().as_millis() as u64;
+                    let rtt =
+                        Duration::from_millis(now.saturating_sub(u64::from_le_bytes(sent_at)));
+                    self.rtt = Some(rtt);
+                    if let Some(mut player_info) =
+                        self.state.connected_players.get_mut(&ctx.address())
+                    {
+                        player_info.rtt = Some(rtt);
+                    }
+                }
+            }
+            // Answer the close handshake, the actor stopping does the rest of the cleanup
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(ws::Message::Continuation(_)) | Ok(ws::Message::Nop) => (),
+            Err(_) => ctx.stop(),
         }
     }
 }
 
-#[derive(Debug, Serialize)]
-struct EnemyInfo {
-    coords: Coords,
-    dir: f32,
-    username: String,
-    health: f32,
-}
-
-// Final websocket response to client
-#[derive(Debug, Serialize)]
-struct GameResponse {
-    my_coords: Coords,
-    health: f32,
-    enemies: Vec<EnemyInfo>,
-    bullets: Vec<Coords>,
-    timestamp: u128,
-}
-
 // Routes state info from the physics engine back to the client
+// Our view is derived from the world snapshot shared by every connection
+// With the BinaryDelta encoding the snapshot is sent as a delta against the last one the client acknowledged
 impl Handler<PhysicsStateResponse> for Ws {
     type Result = ();
 
     fn handle(&mut self, msg: PhysicsStateResponse, ctx: &mut Self::Context) -> Self::Result {
-        // Construct response to client
-        let mut game_response = GameResponse {
-            my_coords: msg.my_coords,
-            health: msg.health,
-            enemies: vec![],
-            bullets: msg.bullets,
-            timestamp: self.start_timestamp.elapsed().as_millis(),
-        };
+        // Clients need to know about an entity before it shows up in a snapshot
+        if !msg.world.spawned.is_empty() {
+            self.send(
+                &ServerMessage::Spawned {
+                    entities: &msg.world.spawned,
+                },
+                ctx,
+            );
+        }
+        if !msg.world.despawned.is_empty() {
+            self.send(
+                &ServerMessage::Despawned {
+                    ids: &msg.world.despawned,
+                },
+                ctx,
+            );
+        }
 
-        for physics_engine::EnemyInfo {
-            coords,
-            ws_address,
-            health,
-            dir,
-        } in msg.enemies.iter()
-        {
-            if let Some(player_info) = self.state.connected_players.get(ws_address) {
-                let username = player_info.username.clone();
-                let enemy = EnemyInfo {
-                    coords: *coords,
-                    health: *health,
-                    dir: *dir,
-                    username,
-                };
-                game_response.enemies.push(enemy);
-            }
+        // Spectators look at the middle of the arena
+        let center = self.state.arena().map.center();
+        let snapshot = Snapshot {
+            seq: self.snapshot_encoder.next_seq(),
+            timestamp: self.start_timestamp.elapsed().as_millis() as u32,
+            rtt_ms: self
+                .rtt
+                .map_or(0, |rtt| rtt.as_millis().min(u16::MAX as u128) as u16),
+            ..Snapshot::view(&msg.world, msg.me, msg.visible.as_deref(), center)
+        };
+        match self.encoding {
+            Some(Encoding::BinaryDelta) => ctx.binary(self.snapshot_encoder.encode(snapshot)),
+            Some(Encoding::Json) => self.send(&ServerMessage::Snapshot(&snapshot), ctx),
+            None => (),
         }
-        ctx.text(serde_json::to_string(&game_response).unwrap())
+    }
+}
+
+impl Handler<KnownEntities> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: KnownEntities, ctx: &mut Self::Context) -> Self::Result {
+        self.send(
+            &ServerMessage::Spawned {
+                entities: &msg.entities,
+            },
+            ctx,
+        );
+    }
+}
+
+impl Handler<GameEvents> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: GameEvents, ctx: &mut Self::Context) -> Self::Result {
+        self.send(
+            &ServerMessage::Events {
+                events: &msg.events,
+            },
+            ctx,
+        );
     }
 }
 
 impl Handler<GameOver> for Ws {
     type Result = ();
 
-    fn handle(&mut self, _msg: GameOver, ctx: &mut Self::Context) -> Self::Result {
-        ctx.text("game_over");
-        ctx.stop();
+    fn handle(&mut self, msg: GameOver, ctx: &mut Self::Context) -> Self::Result {
+        self.send(&ServerMessage::GameOver(&msg), ctx);
+        self.respawn_at = Some(Instant::now() + Duration::from_millis(msg.respawn_in_ms));
+        let _ = self.transition(Phase::Dead, ctx);
+    }
+}
+
+impl Handler<ArenaChanged> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: ArenaChanged, ctx: &mut Self::Context) -> Self::Result {
+        // Clients learn about the arena in Welcome
+        if self.protocol_version.is_none() {
+            return;
+        }
+        self.send(
+            &ServerMessage::MapChange {
+                map: &msg.map,
+                settings: &msg.settings,
+            },
+            ctx,
+        );
+        // Nothing of the old arena is worth a delta against
+        self.snapshot_encoder.request_keyframe();
+    }
+}
+
+impl Handler<MapEdited> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: MapEdited, ctx: &mut Self::Context) -> Self::Result {
+        // Clients learn about the map in Welcome
+        if self.protocol_version.is_some() {
+            self.send(&ServerMessage::MapEdited { map: &msg.map }, ctx);
+        }
+    }
+}
+
+// Edits are rejected without a strike, they are well formed and come from an admin
+impl Handler<EditRejected> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: EditRejected, ctx: &mut Self::Context) -> Self::Result {
+        self.send(
+            &ServerMessage::Error {
+                code: ErrorCode::EditRejected,
+                message: msg.reason,
+            },
+            ctx,
+        );
+    }
+}
+
+impl Handler<MapSaved> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, msg: MapSaved, ctx: &mut Self::Context) -> Self::Result {
+        self.send(&ServerMessage::MapSaved { name: &msg.name }, ctx);
+    }
+}
+
+impl Handler<Respawned> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Respawned, ctx: &mut Self::Context) -> Self::Result {
+        let _ = self.transition(Phase::Joined, ctx);
     }
 }
 
@@ -205,6 +593,15 @@ pub async fn index_ws(
         Ws {
             state: state.clone(),
             start_timestamp: Instant::now(),
+            snapshot_encoder: SnapshotEncoder::new(),
+            protocol_version: None,
+            encoding: None,
+            phase: Phase::Connected,
+            strikes: 0,
+            last_heard: Instant::now(),
+            rtt: None,
+            admin: false,
+            respawn_at: None,
         },
         &req,
         stream,
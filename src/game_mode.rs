@@ -0,0 +1,619 @@
+use crate::components::TeamId;
+use crate::custom_ws::Ws;
+use crate::physics_engine::{Audience, Coords, EntityId, GameEvent, PhysicsEngine};
+use actix::Addr;
+use rapier2d::prelude::ColliderHandle;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Ticks a bullet lives for before it is removed
+const BULLET_LIFETIME: u32 = 500;
+
+// Game modes that can be picked with Settings::game_mode
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum GameModeKind {
+    #[default]
+    FreeForAll,
+    TeamDeathmatch,
+    CaptureTheFlag,
+}
+
+impl GameModeKind {
+    pub fn create(self) -> Box<dyn GameMode> {
+        match self {
+            GameModeKind::FreeForAll => Box::new(FreeForAll),
+            GameModeKind::TeamDeathmatch => Box::new(TeamDeathmatch::default()),
+            GameModeKind::CaptureTheFlag => Box::new(CaptureTheFlag::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TeamScore {
+    pub team: TeamId,
+    pub score: u32,
+}
+
+// A death, with who the physics engine credited for it
+#[derive(Debug)]
+pub struct Kill {
+    pub victim: EntityId,
+    // Where the victim died
+    pub coords: Coords,
+    pub killer: Option<EntityId>,
+    pub assists: Vec<EntityId>,
+}
+
+/// Rules of the game on top of the physics
+/// A fresh mode is made for every arena, so a round lasts at most as long as its arena
+/// The engine never calls a hook from inside another one, which is why kills and round ends
+/// asked for by a mode are only queued, see PhysicsEngine::kill and PhysicsEngine::end_round
+pub trait GameMode {
+    // A connection joined the game, before its first spawn
+    // Also called for everyone still connected when a new arena starts
+    fn on_join(&mut self, _engine: &mut PhysicsEngine, _player: &Addr<Ws>) {}
+
+    // A connection that had joined the game is gone, players that go on to spectate keep their place
+    fn on_leave(&mut self, _engine: &mut PhysicsEngine, _player: &Addr<Ws>) {}
+
+    // A player got a body, on joining and on every respawn
+    fn on_spawn(&mut self, _engine: &mut PhysicsEngine, _player: &Addr<Ws>, _id: EntityId) {}
+
+    // How much damage a hit does before armor, 0 to ignore it
+    // attacker is whoever fired the bullet, if it was a bullet
+    fn on_damage(
+        &mut self,
+        engine: &PhysicsEngine,
+        attacker: Option<EntityId>,
+        target: EntityId,
+        damage: f32,
+    ) -> f32;
+
+    // A player died, their body is already gone
+    fn on_kill(&mut self, _engine: &mut PhysicsEngine, _kill: &Kill) {}
+
+    // Once per tick, after the physics step and damage
+    fn on_tick(&mut self, engine: &mut PhysicsEngine);
+
+    // The arena is about to be torn down, whether the mode ended the round or the rotation moved on
+    fn on_round_end(&mut self, _engine: &mut PhysicsEngine) {}
+}
+
+// Everyone against everyone, until the map rotation moves on
+pub struct FreeForAll;
+
+impl GameMode for FreeForAll {
+    fn on_damage(
+        &mut self,
+        engine: &PhysicsEngine,
+        attacker: Option<EntityId>,
+        target: EntityId,
+        damage: f32,
+    ) -> f32 {
+        if attacker == Some(target) && !engine.settings().self_damage {
+            return 0.0;
+        }
+        damage
+    }
+
+    fn on_tick(&mut self, engine: &mut PhysicsEngine) {
+        standard_tick(engine);
+    }
+}
+
+// Players out of health die, and bullets don't last forever
+fn standard_tick(engine: &mut PhysicsEngine) {
+    let dead: Vec<_> = engine.components().dead().collect();
+    for id in dead {
+        engine.kill(id);
+    }
+    engine.remove_bullets_older_than(BULLET_LIFETIME);
+}
+
+// Team of every player, shared by the team game modes
+// Teams are numbered from 1 up to Settings::teams
+#[derive(Default)]
+struct Teams {
+    // Kept across respawns, until the connection leaves
+    by_connection: HashMap<Addr<Ws>, TeamId>,
+    // Team of every player that spawned this round, kept after they die
+    // so that a killer who died before their victim still scores
+    by_entity: HashMap<EntityId, TeamId>,
+    scores: HashMap<TeamId, u32>,
+}
+
+impl Teams {
+    fn all(engine: &PhysicsEngine) -> std::ops::RangeInclusive<TeamId> {
+        1..=engine.settings().teams.clamp(1, u8::MAX as u32)
+    }
+
+    // Joins the team with the fewest players, the lowest numbered one on a tie
+    fn join(&mut self, engine: &PhysicsEngine, player: &Addr<Ws>) {
+        if self.by_connection.contains_key(player) {
+            return;
+        }
+        let team = Teams::all(engine)
+            .min_by_key(|&team| self.by_connection.values().filter(|&&t| t == team).count())
+            .unwrap();
+        self.by_connection.insert(player.clone(), team);
+    }
+
+    fn leave(&mut self, player: &Addr<Ws>) {
+        self.by_connection.remove(player);
+    }
+
+    // Puts the new body in its player's team and tells the player the scores
+    fn spawn(&mut self, engine: &mut PhysicsEngine, player: &Addr<Ws>, id: EntityId) {
+        let team = self.by_connection[player];
+        self.by_entity.insert(id, team);
+        if let Some(components) = engine.components_mut().get_mut(id) {
+            components.team = Some(team);
+        }
+        let scores = self.scores(engine);
+        engine.push_event(
+            Audience::Players(vec![id]),
+            GameEvent::TeamScores { scores },
+        );
+    }
+
+    fn team_of(&self, id: EntityId) -> Option<TeamId> {
+        self.by_entity.get(&id).copied()
+    }
+
+    // Self damage follows Settings::self_damage, and damage between teammates Settings::friendly_fire
+    fn damage(
+        &self,
+        engine: &PhysicsEngine,
+        attacker: Option<EntityId>,
+        target: EntityId,
+        damage: f32,
+    ) -> f32 {
+        let settings = engine.settings();
+        if attacker == Some(target) && !settings.self_damage {
+            return 0.0;
+        }
+        let teammates = attacker.is_some_and(|attacker| {
+            attacker != target
+                && self.team_of(attacker).is_some()
+                && self.team_of(attacker) == self.team_of(target)
+        });
+        if teammates && !settings.friendly_fire {
+            return 0.0;
+        }
+        damage
+    }
+
+    // Gives a team a point, ending the round once it has limit of them unless limit is 0
+    fn score(&mut self, engine: &mut PhysicsEngine, team: TeamId, limit: u32) {
+        let score = self.scores.entry(team).or_insert(0);
+        *score += 1;
+        if limit > 0 && *score >= limit {
+            engine.end_round();
+        }
+        let scores = self.scores(engine);
+        engine.push_event(Audience::Everyone, GameEvent::TeamScores { scores });
+    }
+
+    fn scores(&self, engine: &PhysicsEngine) -> Vec<TeamScore> {
+        Teams::all(engine)
+            .map(|team| TeamScore {
+                team,
+                score: self.scores.get(&team).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+
+    fn round_over(&self, engine: &mut PhysicsEngine) {
+        let scores = self.scores(engine);
+        let best = scores.iter().map(|s| s.score).max().unwrap_or(0);
+        let mut leaders = scores.iter().filter(|s| s.score == best);
+        // A draw if more than one team has the best score
+        let winner = match (leaders.next(), leaders.next()) {
+            (Some(leader), None) => Some(leader.team),
+            _ => None,
+        };
+        engine.push_event(Audience::Everyone, GameEvent::RoundOver { winner, scores });
+    }
+}
+
+// Teams score a point per enemy killed
+// The round ends when a team reaches Settings::kill_limit
+#[derive(Default)]
+pub struct TeamDeathmatch {
+    teams: Teams,
+}
+
+impl GameMode for TeamDeathmatch {
+    fn on_join(&mut self, engine: &mut PhysicsEngine, player: &Addr<Ws>) {
+        self.teams.join(engine, player);
+    }
+
+    fn on_leave(&mut self, _engine: &mut PhysicsEngine, player: &Addr<Ws>) {
+        self.teams.leave(player);
+    }
+
+    fn on_spawn(&mut self, engine: &mut PhysicsEngine, player: &Addr<Ws>, id: EntityId) {
+        self.teams.spawn(engine, player, id);
+    }
+
+    fn on_damage(
+        &mut self,
+        engine: &PhysicsEngine,
+        attacker: Option<EntityId>,
+        target: EntityId,
+        damage: f32,
+    ) -> f32 {
+        self.teams.damage(engine, attacker, target, damage)
+    }
+
+    fn on_kill(&mut self, engine: &mut PhysicsEngine, kill: &Kill) {
+        let victim = self.teams.team_of(kill.victim);
+        let killer = kill.killer.and_then(|killer| self.teams.team_of(killer));
+        if let Some(team) = killer.filter(|&team| Some(team) != victim) {
+            let kill_limit = engine.settings().kill_limit;
+            self.teams.score(engine, team, kill_limit);
+        }
+    }
+
+    fn on_tick(&mut self, engine: &mut PhysicsEngine) {
+        standard_tick(engine);
+    }
+
+    fn on_round_end(&mut self, engine: &mut PhysicsEngine) {
+        self.teams.round_over(engine);
+    }
+}
+
+// Players pick up a flag by touching it
+const FLAG_RADIUS: f32 = 30.0;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum FlagAction {
+    // An enemy picked the flag up
+    Taken,
+    // Its carrier died
+    Dropped,
+    // Back at its base, touched by its own team or its carrier left
+    Returned,
+    // Brought to the carrier's base, and back at its own
+    Captured,
+}
+
+// Where a team's flag is, as sent to clients
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagInfo {
+    pub team: TeamId,
+    pub coords: Coords,
+    // Player carrying the flag, coords is where it was picked up then
+    pub carrier: Option<EntityId>,
+}
+
+struct Flag {
+    team: TeamId,
+    base: Coords,
+    coords: Coords,
+    // Only lying flags have a sensor
+    sensor: Option<ColliderHandle>,
+    carrier: Option<EntityId>,
+}
+
+impl Flag {
+    fn at_base(&self) -> bool {
+        self.carrier.is_none() && self.coords == self.base
+    }
+
+    // Puts the flag down, it can be touched from then on
+    fn lay(&mut self, engine: &mut PhysicsEngine, coords: Coords) {
+        if let Some(sensor) = self.sensor.take() {
+            engine.remove_sensor(sensor);
+        }
+        self.carrier = None;
+        self.coords = coords;
+        self.sensor = Some(engine.add_sensor(coords, FLAG_RADIUS));
+    }
+
+    fn pick_up(&mut self, engine: &mut PhysicsEngine, carrier: EntityId) {
+        if let Some(sensor) = self.sensor.take() {
+            engine.remove_sensor(sensor);
+        }
+        self.carrier = Some(carrier);
+        engine.set_flag(carrier, Some(self.team));
+    }
+}
+
+// Every team has a flag at its base, the zone named base<team> of the map
+// Bringing the enemy flag to your own base while your flag is there scores a capture,
+// and the round ends when a team reaches Settings::capture_limit
+// Teams without a base in the map have no flag, and can't capture
+#[derive(Default)]
+pub struct CaptureTheFlag {
+    teams: Teams,
+    // Put at the bases on the first tick
+    flags: Option<Vec<Flag>>,
+}
+
+impl CaptureTheFlag {
+    fn place_flags(engine: &mut PhysicsEngine) -> Vec<Flag> {
+        let bases: Vec<_> = Teams::all(engine)
+            .filter_map(|team| {
+                let zone = engine.map().zone(&format!("base{}", team))?;
+                Some((team, zone.shape.center()))
+            })
+            .collect();
+        bases
+            .into_iter()
+            .map(|(team, base)| {
+                let mut flag = Flag {
+                    team,
+                    base,
+                    coords: base,
+                    sensor: None,
+                    carrier: None,
+                };
+                flag.lay(engine, base);
+                flag
+            })
+            .collect()
+    }
+
+    fn flag_infos(&self) -> Vec<FlagInfo> {
+        self.flags
+            .iter()
+            .flatten()
+            .map(|flag| FlagInfo {
+                team: flag.team,
+                coords: flag.coords,
+                carrier: flag.carrier,
+            })
+            .collect()
+    }
+
+    fn announce(
+        &self,
+        engine: &mut PhysicsEngine,
+        team: TeamId,
+        action: FlagAction,
+        by: Option<EntityId>,
+    ) {
+        engine.push_event(Audience::Everyone, GameEvent::Flag { team, action, by });
+        let flags = self.flag_infos();
+        engine.push_event(Audience::Everyone, GameEvent::Flags { flags });
+    }
+
+    // A player touched the flag with the given sensor
+    fn touch(&mut self, engine: &mut PhysicsEngine, sensor: ColliderHandle, player: EntityId) {
+        let team = match self.teams.team_of(player) {
+            Some(team) => team,
+            None => return,
+        };
+        let flags = self.flags.get_or_insert_with(Vec::new);
+        let touched = match flags.iter().position(|flag| flag.sensor == Some(sensor)) {
+            Some(touched) => touched,
+            None => return,
+        };
+
+        if flags[touched].team != team {
+            let enemy = flags[touched].team;
+            flags[touched].pick_up(engine, player);
+            self.announce(engine, enemy, FlagAction::Taken, Some(player));
+        } else if !flags[touched].at_base() {
+            let base = flags[touched].base;
+            flags[touched].lay(engine, base);
+            self.announce(engine, team, FlagAction::Returned, Some(player));
+        } else if let Some(carried) = flags.iter().position(|flag| flag.carrier == Some(player)) {
+            let (enemy, base) = (flags[carried].team, flags[carried].base);
+            flags[carried].lay(engine, base);
+            engine.set_flag(player, None);
+            let capture_limit = engine.settings().capture_limit;
+            self.teams.score(engine, team, capture_limit);
+            self.announce(engine, enemy, FlagAction::Captured, Some(player));
+        }
+    }
+}
+
+impl GameMode for CaptureTheFlag {
+    fn on_join(&mut self, engine: &mut PhysicsEngine, player: &Addr<Ws>) {
+        self.teams.join(engine, player);
+    }
+
+    fn on_leave(&mut self, _engine: &mut PhysicsEngine, player: &Addr<Ws>) {
+        self.teams.leave(player);
+    }
+
+    fn on_spawn(&mut self, engine: &mut PhysicsEngine, player: &Addr<Ws>, id: EntityId) {
+        self.teams.spawn(engine, player, id);
+        let flags = self.flag_infos();
+        engine.push_event(Audience::Players(vec![id]), GameEvent::Flags { flags });
+    }
+
+    fn on_damage(
+        &mut self,
+        engine: &PhysicsEngine,
+        attacker: Option<EntityId>,
+        target: EntityId,
+        damage: f32,
+    ) -> f32 {
+        self.teams.damage(engine, attacker, target, damage)
+    }
+
+    // The flag falls where its carrier died
+    fn on_kill(&mut self, engine: &mut PhysicsEngine, kill: &Kill) {
+        let flags = self.flags.get_or_insert_with(Vec::new);
+        if let Some(flag) = flags.iter_mut().find(|f| f.carrier == Some(kill.victim)) {
+            flag.lay(engine, kill.coords);
+            let team = flag.team;
+            self.announce(engine, team, FlagAction::Dropped, None);
+        }
+    }
+
+    fn on_tick(&mut self, engine: &mut PhysicsEngine) {
+        if self.flags.is_none() {
+            self.flags = Some(CaptureTheFlag::place_flags(engine));
+            let flags = self.flag_infos();
+            engine.push_event(Audience::Everyone, GameEvent::Flags { flags });
+        }
+        standard_tick(engine);
+
+        // Carriers that left the game take the flag back to its base
+        let flags = self.flags.get_or_insert_with(Vec::new);
+        let mut returned = vec![];
+        for flag in flags.iter_mut() {
+            if flag
+                .carrier
+                .is_some_and(|id| engine.player_position(id).is_none())
+            {
+                let base = flag.base;
+                flag.lay(engine, base);
+                returned.push(flag.team);
+            }
+        }
+        for team in returned {
+            self.announce(engine, team, FlagAction::Returned, None);
+        }
+
+        for (sensor, player) in engine.sensor_contacts() {
+            self.touch(engine, sensor, player);
+        }
+    }
+
+    fn on_round_end(&mut self, engine: &mut PhysicsEngine) {
+        self.teams.round_over(engine);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{InnerState, Settings};
+    use actix::dev::channel::channel;
+    use std::sync::Arc;
+
+    fn engine(friendly_fire: bool) -> PhysicsEngine {
+        let mut settings = Settings::load();
+        settings.teams = 2;
+        settings.friendly_fire = friendly_fire;
+        settings.kill_limit = 0;
+        PhysicsEngine::new(Arc::new(InnerState::with_settings(settings)))
+    }
+
+    // An address whose receiver is gone, so anything sent to it goes nowhere
+    fn address() -> Addr<Ws> {
+        Addr::new(channel::<Ws>(16).0)
+    }
+
+    // Joins and spawns players, player i gets entity id i
+    fn join(mode: &mut dyn GameMode, engine: &mut PhysicsEngine, players: &[Addr<Ws>]) {
+        for (id, player) in players.iter().enumerate() {
+            mode.on_join(engine, player);
+            mode.on_spawn(engine, player, id as EntityId);
+        }
+    }
+
+    #[test]
+    fn teams_are_balanced() {
+        let mut engine = engine(false);
+        let mut mode = TeamDeathmatch::default();
+        let players: Vec<_> = (0..4).map(|_| address()).collect();
+        join(&mut mode, &mut engine, &players[..3]);
+        assert_eq!(mode.teams.by_connection[&players[0]], 1);
+        assert_eq!(mode.teams.by_connection[&players[1]], 2);
+        assert_eq!(mode.teams.by_connection[&players[2]], 1);
+
+        mode.on_leave(&mut engine, &players[0]);
+        mode.on_leave(&mut engine, &players[2]);
+        mode.on_join(&mut engine, &players[3]);
+        assert_eq!(mode.teams.by_connection[&players[3]], 1);
+        // Joining again keeps the team
+        mode.on_join(&mut engine, &players[1]);
+        assert_eq!(mode.teams.by_connection[&players[1]], 2);
+    }
+
+    #[test]
+    fn friendly_fire_is_configurable() {
+        let players: Vec<_> = (0..3).map(|_| address()).collect();
+        for friendly_fire in [false, true] {
+            let mut engine = engine(friendly_fire);
+            let mut mode = TeamDeathmatch::default();
+            join(&mut mode, &mut engine, &players);
+            // 0 and 2 are teammates
+            let expected = if friendly_fire { 10.0 } else { 0.0 };
+            assert_eq!(mode.on_damage(&engine, Some(0), 2, 10.0), expected);
+            assert_eq!(mode.on_damage(&engine, Some(0), 1, 10.0), 10.0);
+            assert_eq!(mode.on_damage(&engine, None, 1, 10.0), 10.0);
+        }
+    }
+
+    #[test]
+    fn only_enemy_kills_score() {
+        let mut engine = engine(true);
+        let mut mode = TeamDeathmatch::default();
+        let players: Vec<_> = (0..3).map(|_| address()).collect();
+        join(&mut mode, &mut engine, &players);
+        let kill = |victim, killer| Kill {
+            victim,
+            coords: Coords { x: 0.0, y: 0.0 },
+            killer,
+            assists: vec![],
+        };
+        mode.on_kill(&mut engine, &kill(2, Some(0)));
+        mode.on_kill(&mut engine, &kill(1, None));
+        assert!(mode.teams.scores(&engine).iter().all(|s| s.score == 0));
+
+        mode.on_spawn(&mut engine, &players[1], 3);
+        mode.on_kill(&mut engine, &kill(3, Some(0)));
+        let scores: Vec<_> = mode.teams.scores(&engine).iter().map(|s| s.score).collect();
+        assert_eq!(scores, vec![1, 0]);
+
+        // A killer who died before their victim still scores
+        mode.on_spawn(&mut engine, &players[1], 4);
+        mode.on_kill(&mut engine, &kill(0, Some(4)));
+        mode.on_kill(&mut engine, &kill(4, Some(0)));
+        let scores: Vec<_> = mode.teams.scores(&engine).iter().map(|s| s.score).collect();
+        assert_eq!(scores, vec![2, 1]);
+    }
+
+    #[test]
+    fn flags_are_taken_dropped_returned_and_captured() {
+        let mut engine = engine(false);
+        let mut mode = CaptureTheFlag::default();
+        let players: Vec<_> = (0..2).map(|_| address()).collect();
+        join(&mut mode, &mut engine, &players);
+        mode.flags = Some(CaptureTheFlag::place_flags(&mut engine));
+        fn flag(mode: &CaptureTheFlag, team: usize) -> &Flag {
+            &mode.flags.as_ref().unwrap()[team - 1]
+        }
+        let sensor = |mode: &CaptureTheFlag, team| flag(mode, team).sensor;
+
+        // Player 1 of team 2 takes the flag of team 1, and drops it on death
+        mode.touch(&mut engine, sensor(&mode, 1).unwrap(), 1);
+        assert_eq!(flag(&mode, 1).carrier, Some(1));
+        assert!(sensor(&mode, 1).is_none());
+        let coords = Coords { x: 500.0, y: 500.0 };
+        mode.on_kill(
+            &mut engine,
+            &Kill {
+                victim: 1,
+                coords,
+                killer: None,
+                assists: vec![],
+            },
+        );
+        assert_eq!(flag(&mode, 1).carrier, None);
+        assert_eq!(flag(&mode, 1).coords, coords);
+
+        // Player 0 returns it
+        mode.touch(&mut engine, sensor(&mode, 1).unwrap(), 0);
+        assert!(flag(&mode, 1).at_base());
+
+        // Touching your own flag at base without carrying anything does nothing
+        mode.on_spawn(&mut engine, &players[1], 2);
+        mode.touch(&mut engine, sensor(&mode, 2).unwrap(), 2);
+        assert!(flag(&mode, 2).at_base());
+
+        mode.touch(&mut engine, sensor(&mode, 1).unwrap(), 2);
+        mode.touch(&mut engine, sensor(&mode, 2).unwrap(), 2);
+        assert!(flag(&mode, 1).at_base());
+        let scores: Vec<_> = mode.teams.scores(&engine).iter().map(|s| s.score).collect();
+        assert_eq!(scores, vec![0, 1]);
+    }
+}
@@ -0,0 +1,383 @@
+use crate::map::{Map, Shape, Zone};
+use crate::physics_engine::Coords;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+// Placement attempts per requested obstacle before giving up on it
+const ATTEMPTS: u32 = 20;
+
+// Size parameters of a procedurally generated arena
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Generator {
+    // Picked at random every time the arena is loaded if left out
+    #[serde(default)]
+    pub seed: Option<u32>,
+    pub width: f32,
+    pub height: f32,
+    // Large standalone obstacles
+    #[serde(default)]
+    pub obstacles: u32,
+    // Groups of small crates to take cover behind
+    #[serde(default)]
+    pub clusters: u32,
+    pub spawn_points: u32,
+}
+
+// Coarse grid of the places a player fits in
+// Cells are as wide as a player, and a cell is walkable if a player can stand anywhere in it,
+// so a player can always walk in a straight line between two walkable neighbours
+struct Grid {
+    cell: f32,
+    columns: usize,
+    rows: usize,
+    walkable: Vec<bool>,
+}
+
+impl Generator {
+    /// Builds a rectangular arena with obstacles, cover and spawn points laid out from the seed
+    /// Every spawn point can be walked to from every other one by a player of the given radius
+    pub fn generate(&self, name: &str, seed: u32, player_radius: f32) -> Result<Map, String> {
+        if self.width <= 0.0 || self.height <= 0.0 || player_radius <= 0.0 {
+            return Err(format!("map {:?} needs a positive size", name));
+        }
+        if self.spawn_points == 0 {
+            return Err(format!("map {:?} needs at least 1 spawn point", name));
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed as u64);
+        let mut grid = Grid::new(self.width, self.height, player_radius);
+        if grid.walkable_count() < self.spawn_points as usize {
+            return Err(format!("map {:?} is too small", name));
+        }
+        let mut obstacles = Vec::new();
+        let smallest = self.width.min(self.height);
+
+        for _ in 0..self.obstacles {
+            for _ in 0..ATTEMPTS {
+                let center = self.random_point(&mut rng);
+                let shape = if rng.gen_bool(0.5) {
+                    Shape::Cuboid {
+                        center,
+                        half_width: rng.gen_range(0.02..0.08) * smallest,
+                        half_height: rng.gen_range(0.02..0.08) * smallest,
+                        rotation: rng.gen_range(0.0..PI),
+                    }
+                } else {
+                    Shape::Circle {
+                        center,
+                        radius: rng.gen_range(0.02..0.06) * smallest,
+                    }
+                };
+                if self.place(shape, &mut grid, &mut obstacles) {
+                    break;
+                }
+            }
+        }
+
+        for _ in 0..self.clusters {
+            let center = self.random_point(&mut rng);
+            for _ in 0..rng.gen_range(3..=5) {
+                for _ in 0..ATTEMPTS {
+                    let (angle, distance) = (rng.gen_range(0.0..2.0 * PI), rng.gen_range(0.0..6.0));
+                    let half_size = rng.gen_range(1.5..2.5) * player_radius;
+                    let shape = Shape::Cuboid {
+                        center: Coords {
+                            x: center.x + angle.cos() * distance * player_radius,
+                            y: center.y + angle.sin() * distance * player_radius,
+                        },
+                        half_width: half_size,
+                        half_height: half_size,
+                        rotation: rng.gen_range(-0.3..0.3),
+                    };
+                    if self.place(shape, &mut grid, &mut obstacles) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut spawn_points = grid.spread(self.spawn_points as usize, &mut rng);
+        furthest_pair_first(&mut spawn_points);
+        let center = Coords {
+            x: self.width / 2.0,
+            y: self.height / 2.0,
+        };
+        let mut zones = vec![Zone {
+            name: "center".to_owned(),
+            shape: Shape::Circle {
+                center,
+                radius: 0.1 * smallest,
+            },
+        }];
+        // Team bases go on the two spawn points furthest apart
+        for (team, &center) in spawn_points.iter().take(2).enumerate() {
+            zones.push(Zone {
+                name: format!("base{}", team + 1),
+                shape: Shape::Circle {
+                    center,
+                    radius: player_radius * 4.0,
+                },
+            });
+        }
+        Ok(Map {
+            name: name.to_owned(),
+            boundary: vec![
+                Coords { x: 0.0, y: 0.0 },
+                Coords {
+                    x: self.width,
+                    y: 0.0,
+                },
+                Coords {
+                    x: self.width,
+                    y: self.height,
+                },
+                Coords {
+                    x: 0.0,
+                    y: self.height,
+                },
+            ],
+            obstacles,
+            spawn_points,
+            zones,
+            seed: Some(seed),
+        })
+    }
+
+    fn random_point(&self, rng: &mut StdRng) -> Coords {
+        Coords {
+            x: rng.gen_range(0.0..self.width),
+            y: rng.gen_range(0.0..self.height),
+        }
+    }
+
+    // Adds the shape unless it would stick out of the arena or cut the walkable area in pieces
+    fn place(&self, shape: Shape, grid: &mut Grid, obstacles: &mut Vec<Shape>) -> bool {
+        let (center, reach) = match &shape {
+            Shape::Cuboid {
+                center,
+                half_width,
+                half_height,
+                ..
+            } => (*center, half_width.hypot(*half_height)),
+            Shape::Circle { center, radius } => (*center, *radius),
+            Shape::Polygon { .. } => return false,
+        };
+        if center.x - reach < 0.0
+            || center.y - reach < 0.0
+            || center.x + reach > self.width
+            || center.y + reach > self.height
+        {
+            return false;
+        }
+        let previous = grid.walkable.clone();
+        grid.block(&shape);
+        if grid.walkable_count() < self.spawn_points as usize || !grid.is_connected() {
+            grid.walkable = previous;
+            return false;
+        }
+        obstacles.push(shape);
+        true
+    }
+}
+
+impl Grid {
+    fn new(width: f32, height: f32, player_radius: f32) -> Grid {
+        let cell = player_radius * 2.0;
+        let (columns, rows) = ((width / cell) as usize, (height / cell) as usize);
+        let mut grid = Grid {
+            cell,
+            columns,
+            rows,
+            walkable: vec![true; columns * rows],
+        };
+        // Keep players off the walls
+        for i in 0..grid.walkable.len() {
+            let p = grid.center(i);
+            grid.walkable[i] = p.x.min(p.y).min(width - p.x).min(height - p.y) >= grid.clearance();
+        }
+        grid
+    }
+
+    // A player at most half a cell away from the center of a walkable cell touches nothing
+    fn clearance(&self) -> f32 {
+        self.cell
+    }
+
+    fn center(&self, i: usize) -> Coords {
+        Coords {
+            x: ((i % self.columns) as f32 + 0.5) * self.cell,
+            y: ((i / self.columns) as f32 + 0.5) * self.cell,
+        }
+    }
+
+    fn block(&mut self, shape: &Shape) {
+        for i in 0..self.walkable.len() {
+            if self.walkable[i] && shape.distance(self.center(i)) < self.clearance() {
+                self.walkable[i] = false;
+            }
+        }
+    }
+
+    fn walkable_count(&self) -> usize {
+        self.walkable.iter().filter(|&&w| w).count()
+    }
+
+    fn neighbours(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        let (x, y) = (i % self.columns, i / self.columns);
+        [
+            (x > 0).then(|| i - 1),
+            (x + 1 < self.columns).then(|| i + 1),
+            (y > 0).then(|| i - self.columns),
+            (y + 1 < self.rows).then(|| i + self.columns),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|&n| self.walkable[n])
+    }
+
+    // Whether every walkable cell can be reached from every other one
+    fn is_connected(&self) -> bool {
+        let Some(start) = self.walkable.iter().position(|&w| w) else {
+            return false;
+        };
+        let mut seen = vec![false; self.walkable.len()];
+        seen[start] = true;
+        let mut queue = VecDeque::from([start]);
+        let mut reached = 1;
+        while let Some(i) = queue.pop_front() {
+            for n in self.neighbours(i) {
+                if !seen[n] {
+                    seen[n] = true;
+                    reached += 1;
+                    queue.push_back(n);
+                }
+            }
+        }
+        reached == self.walkable_count()
+    }
+
+    // Picks walkable cells as far apart from each other as possible
+    fn spread(&self, count: usize, rng: &mut StdRng) -> Vec<Coords> {
+        let cells: Vec<Coords> = (0..self.walkable.len())
+            .filter(|&i| self.walkable[i])
+            .map(|i| self.center(i))
+            .collect();
+        let mut picked = vec![cells[rng.gen_range(0..cells.len())]];
+        while picked.len() < count {
+            let furthest = cells
+                .iter()
+                .map(|&c| {
+                    let nearest = picked
+                        .iter()
+                        .map(|p| (p.x - c.x).hypot(p.y - c.y))
+                        .fold(f32::INFINITY, f32::min);
+                    (c, nearest)
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            picked.push(furthest.0);
+        }
+        picked
+    }
+}
+
+// Moves the two points that are furthest apart to the front
+fn furthest_pair_first(points: &mut [Coords]) {
+    let distance =
+        |(i, j): (usize, usize)| (points[i].x - points[j].x).hypot(points[i].y - points[j].y);
+    let furthest = (0..points.len())
+        .flat_map(|i| (i + 1..points.len()).map(move |j| (i, j)))
+        .max_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+    if let Some((i, j)) = furthest {
+        points.swap(0, i);
+        points.swap(1, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> Generator {
+        Generator {
+            seed: None,
+            width: 2000.0,
+            height: 1500.0,
+            obstacles: 15,
+            clusters: 5,
+            spawn_points: 8,
+        }
+    }
+
+    #[test]
+    fn same_seed_same_layout() {
+        let a = generator().generate("a", 42, 20.0).unwrap();
+        let b = generator().generate("a", 42, 20.0).unwrap();
+        assert_eq!(
+            serde_json::to_value(&a).unwrap(),
+            serde_json::to_value(&b).unwrap()
+        );
+        let c = generator().generate("a", 43, 20.0).unwrap();
+        assert_ne!(
+            serde_json::to_value(&a).unwrap(),
+            serde_json::to_value(&c).unwrap()
+        );
+        assert_eq!(a.seed, Some(42));
+    }
+
+    #[test]
+    fn spawn_points_are_reachable() {
+        for seed in 0..20 {
+            let map = generator().generate("a", seed, 20.0).unwrap();
+            assert_eq!(map.spawn_points.len(), 8);
+            assert!(!map.obstacles.is_empty());
+
+            // Rebuild the grid from the final obstacles and walk it from the first spawn point
+            let mut grid = Grid::new(2000.0, 1500.0, 20.0);
+            for obstacle in &map.obstacles {
+                grid.block(obstacle);
+            }
+            assert!(grid.is_connected());
+            for spawn in &map.spawn_points {
+                assert!(map.contains(*spawn));
+                let i =
+                    (spawn.y / grid.cell) as usize * grid.columns + (spawn.x / grid.cell) as usize;
+                assert!(grid.walkable[i]);
+                for obstacle in &map.obstacles {
+                    assert!(obstacle.distance(*spawn) >= 20.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bases_are_the_spawn_points_furthest_apart() {
+        let distance = |a: Coords, b: Coords| (a.x - b.x).hypot(a.y - b.y);
+        for seed in 0..20 {
+            let map = generator().generate("a", seed, 20.0).unwrap();
+            let base = |name| match map.zone(name).unwrap().shape {
+                Shape::Circle { center, .. } => center,
+                _ => unreachable!(),
+            };
+            let bases = distance(base("base1"), base("base2"));
+            for &a in &map.spawn_points {
+                for &b in &map.spawn_points {
+                    assert!(distance(a, b) <= bases);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn too_small_is_an_error() {
+        let mut generator = generator();
+        generator.width = 50.0;
+        assert!(generator.generate("a", 1, 20.0).is_err());
+        generator.width = 2000.0;
+        generator.spawn_points = 0;
+        assert!(generator.generate("a", 1, 20.0).is_err());
+    }
+}
@@ -0,0 +1,8 @@
+pub mod components;
+pub mod custom_ws;
+pub mod game_mode;
+pub mod generator;
+pub mod map;
+pub mod physics_engine;
+pub mod protocol;
+pub mod state;
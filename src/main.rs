@@ -1,15 +1,81 @@
-mod custom_ws;
-mod physics_engine;
-mod state;
-
-use state::{InnerState, State};
+use multiplayer_shooter::map::Map;
+use multiplayer_shooter::physics_engine::NextArena;
+use multiplayer_shooter::state::{InnerState, Phase, State};
+use multiplayer_shooter::{custom_ws, physics_engine};
 
 use actix::Actor;
 use actix_files as fs;
 use actix_files::NamedFile;
-use actix_web::{get, web, App, Error, HttpRequest, HttpServer, Result};
+use actix_web::http::header;
+use actix_web::{get, post, web, App, Error, HttpRequest, HttpResponse, HttpServer, Result};
+use serde::Serialize;
 use std::{path::PathBuf, sync::Arc};
 
+#[derive(Serialize)]
+struct AdminPlayerInfo {
+    username: String,
+    phase: Phase,
+    rtt_ms: Option<u128>,
+}
+
+// Whether the request carries the admin token as "Authorization: Bearer <token>"
+// Nobody is an admin if the server was started without ADMIN_TOKEN
+fn is_admin(req: &HttpRequest, state: &State) -> bool {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (&state.admin_token, bearer) {
+        (Some(token), Some(bearer)) => token == bearer,
+        _ => false,
+    }
+}
+
+/// Lists every connection with its phase and measured round trip time, admins only
+#[get("/admin/players")]
+async fn admin_players(req: HttpRequest, state: web::Data<State>) -> HttpResponse {
+    if !is_admin(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(
+        state
+            .connected_players
+            .iter()
+            .map(|player| AdminPlayerInfo {
+                username: player.username.clone(),
+                phase: player.phase,
+                rtt_ms: player.rtt.map(|rtt| rtt.as_millis()),
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Skips to the next arena of the map rotation, admins only
+#[post("/admin/next_map")]
+async fn admin_next_map(req: HttpRequest, state: web::Data<State>) -> HttpResponse {
+    if !is_admin(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    state.get_physics_engine().do_send(NextArena);
+    HttpResponse::Accepted().finish()
+}
+
+/// Lists the names of the maps that can be played
+#[get("/maps")]
+async fn maps() -> web::Json<Vec<String>> {
+    web::Json(Map::names())
+}
+
+/// Serves the geometry of a map for clients to render
+#[get("/maps/{name}")]
+async fn map(name: web::Path<String>) -> HttpResponse {
+    match Map::load(&name) {
+        Ok(map) => HttpResponse::Ok().json(map),
+        Err(reason) => HttpResponse::NotFound().body(reason),
+    }
+}
+
 /// Handles HTTP requests for files
 /// Looks in the /static/ directory for file requested
 #[get("/{filename:.*}")]
@@ -47,6 +113,10 @@ async fn main() -> std::io::Result<()> {
             .app_data(app_state.clone())
             // Routes Websocket connections
             .route("/ws/", web::get().to(custom_ws::index_ws))
+            .service(admin_players)
+            .service(admin_next_map)
+            .service(maps)
+            .service(map)
             // Routes file requests
             .service(default_page)
             .service(index)
@@ -0,0 +1,534 @@
+use crate::physics_engine::Coords;
+use rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
+// Map files are read from here, named <name>.json
+const MAPS_DIR: &str = "./maps/";
+
+// Largest coordinate or size a map may use, anything bigger would break the physics
+const MAX_COORDINATE: f32 = 1_000_000.0;
+
+fn sane(value: f32) -> bool {
+    value.is_finite() && value.abs() <= MAX_COORDINATE
+}
+
+fn sane_point(p: &Coords) -> bool {
+    sane(p.x) && sane(p.y)
+}
+
+// Geometry of an arena, loaded from a JSON map file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Map {
+    // Taken from the file name
+    #[serde(default)]
+    pub name: String,
+    // Outline of the arena, walls are built along it and back to the first point
+    pub boundary: Vec<Coords>,
+    #[serde(default)]
+    pub obstacles: Vec<Shape>,
+    // Random free positions are used if none of them is free
+    #[serde(default)]
+    pub spawn_points: Vec<Coords>,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    // Set on generated maps, generating again with it gives the same layout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Shape {
+    Cuboid {
+        center: Coords,
+        half_width: f32,
+        half_height: f32,
+        // Radians, clockwise
+        #[serde(default)]
+        rotation: f32,
+    },
+    Circle {
+        center: Coords,
+        radius: f32,
+    },
+    // Outline of the polygon, which may be concave
+    // Only the outline collides, so nothing should be placed inside it
+    Polygon {
+        points: Vec<Coords>,
+    },
+}
+
+// Change made to the map being played by an admin, see Map::apply
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "op")]
+pub enum MapEdit {
+    PlaceObstacle { shape: Shape },
+    MoveObstacle { index: usize, to: Coords },
+    DeleteObstacle { index: usize },
+    PlaceSpawnPoint { at: Coords },
+    MoveSpawnPoint { index: usize, to: Coords },
+    DeleteSpawnPoint { index: usize },
+    // Writes the map to maps/<name>.json, replacing any map with that name
+    Save { name: String },
+}
+
+// Named area of the map, for game modes to give a meaning to
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Zone {
+    pub name: String,
+    pub shape: Shape,
+}
+
+impl Map {
+    /// Reads and checks maps/<name>.json
+    pub fn load(name: &str) -> Result<Map, String> {
+        if !Map::names().iter().any(|n| n == name) {
+            return Err(format!("no map named {:?}", name));
+        }
+        let path = PathBuf::from(MAPS_DIR).join(format!("{}.json", name));
+        let file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut map: Map = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        map.name = name.to_owned();
+        map.validate()?;
+        Ok(map)
+    }
+
+    /// Names of every map in the maps directory, sorted
+    pub fn names() -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(MAPS_DIR)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != "json" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_owned())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Writes the map to maps/<name>.json
+    pub fn save(&self, name: &str) -> Result<(), String> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(format!(
+                "map names are made of letters, digits, - and _, not {:?}",
+                name
+            ));
+        }
+        let map = Map {
+            name: name.to_owned(),
+            seed: None,
+            ..self.clone()
+        };
+        let path = PathBuf::from(MAPS_DIR).join(format!("{}.json", name));
+        let file = File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &map)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Applies an edit, leaving the map untouched if it would make it invalid
+    /// Saving is left to the caller
+    pub fn apply(&mut self, edit: &MapEdit) -> Result<(), String> {
+        let mut map = self.clone();
+        match edit {
+            MapEdit::PlaceObstacle { shape } => map.obstacles.push(shape.clone()),
+            MapEdit::MoveObstacle { index, to } => {
+                obstacle(&mut map.obstacles, *index)?.move_to(*to)
+            }
+            MapEdit::DeleteObstacle { index } => {
+                obstacle(&mut map.obstacles, *index)?;
+                map.obstacles.remove(*index);
+            }
+            MapEdit::PlaceSpawnPoint { at } => map.spawn_points.push(*at),
+            MapEdit::MoveSpawnPoint { index, to } => {
+                *spawn_point(&mut map.spawn_points, *index)? = *to
+            }
+            MapEdit::DeleteSpawnPoint { index } => {
+                spawn_point(&mut map.spawn_points, *index)?;
+                map.spawn_points.remove(*index);
+            }
+            MapEdit::Save { .. } => (),
+        }
+        map.validate()?;
+        if let Some(i) = map.spawn_points.iter().position(|&p| !map.contains(p)) {
+            return Err(format!(
+                "spawn point {} would be outside of the arena or inside an obstacle",
+                i
+            ));
+        }
+        *self = map;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.boundary.len() < 3 {
+            return Err(format!(
+                "map {:?} needs at least 3 boundary points",
+                self.name
+            ));
+        }
+        if !self.boundary.iter().all(sane_point) {
+            return Err(format!("map {:?} has an invalid boundary point", self.name));
+        }
+        if !self.spawn_points.iter().all(sane_point) {
+            return Err(format!("map {:?} has an invalid spawn point", self.name));
+        }
+        for shape in self
+            .obstacles
+            .iter()
+            .chain(self.zones.iter().map(|z| &z.shape))
+        {
+            shape
+                .validate()
+                .map_err(|reason| format!("map {:?} has {}", self.name, reason))?;
+        }
+        Ok(())
+    }
+
+    // Smallest and largest coordinates of the boundary
+    pub fn bounds(&self) -> (Coords, Coords) {
+        self.boundary.iter().fold(
+            (
+                Coords {
+                    x: f32::INFINITY,
+                    y: f32::INFINITY,
+                },
+                Coords {
+                    x: f32::NEG_INFINITY,
+                    y: f32::NEG_INFINITY,
+                },
+            ),
+            |(min, max), p| {
+                (
+                    Coords {
+                        x: min.x.min(p.x),
+                        y: min.y.min(p.y),
+                    },
+                    Coords {
+                        x: max.x.max(p.x),
+                        y: max.y.max(p.y),
+                    },
+                )
+            },
+        )
+    }
+
+    pub fn center(&self) -> Coords {
+        let (min, max) = self.bounds();
+        Coords {
+            x: (min.x + max.x) / 2.0,
+            y: (min.y + max.y) / 2.0,
+        }
+    }
+
+    // Whether a point is inside the boundary and outside every obstacle
+    pub fn contains(&self, point: Coords) -> bool {
+        polygon_contains(&self.boundary, point) && !self.obstacles.iter().any(|o| o.contains(point))
+    }
+
+    // One segment per side of the boundary
+    pub fn walls(&self) -> Vec<ColliderBuilder> {
+        let n = self.boundary.len();
+        (0..n)
+            .map(|i| {
+                let (a, b) = (self.boundary[i], self.boundary[(i + 1) % n]);
+                ColliderBuilder::segment(point![a.x, a.y], point![b.x, b.y])
+            })
+            .collect()
+    }
+
+    pub fn zone(&self, name: &str) -> Option<&Zone> {
+        self.zones.iter().find(|zone| zone.name == name)
+    }
+}
+
+impl Shape {
+    fn validate(&self) -> Result<(), String> {
+        let size = |s: f32| sane(s) && s > 0.0;
+        let valid = match self {
+            Shape::Cuboid {
+                center,
+                half_width,
+                half_height,
+                rotation,
+            } => {
+                sane_point(center)
+                    && size(*half_width)
+                    && size(*half_height)
+                    && rotation.is_finite()
+            }
+            Shape::Circle { center, radius } => sane_point(center) && size(*radius),
+            Shape::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err(String::from("a polygon with less than 3 points"));
+                }
+                points.iter().all(sane_point)
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("an invalid shape: {:?}", self))
+        }
+    }
+
+    // Center of the cuboid or circle, average of the points of a polygon
+    pub fn center(&self) -> Coords {
+        match self {
+            Shape::Cuboid { center, .. } | Shape::Circle { center, .. } => *center,
+            Shape::Polygon { points } => {
+                let n = points.len().max(1) as f32;
+                Coords {
+                    x: points.iter().map(|p| p.x).sum::<f32>() / n,
+                    y: points.iter().map(|p| p.y).sum::<f32>() / n,
+                }
+            }
+        }
+    }
+
+    pub fn move_to(&mut self, to: Coords) {
+        let from = self.center();
+        match self {
+            Shape::Cuboid { center, .. } | Shape::Circle { center, .. } => *center = to,
+            Shape::Polygon { points } => {
+                for p in points {
+                    p.x += to.x - from.x;
+                    p.y += to.y - from.y;
+                }
+            }
+        }
+    }
+
+    pub fn collider(&self) -> ColliderBuilder {
+        match self {
+            Shape::Cuboid {
+                center,
+                half_width,
+                half_height,
+                rotation,
+            } => ColliderBuilder::cuboid(*half_width, *half_height)
+                .translation(vector![center.x, center.y])
+                .rotation(*rotation),
+            Shape::Circle { center, radius } => {
+                ColliderBuilder::ball(*radius).translation(vector![center.x, center.y])
+            }
+            Shape::Polygon { points } => {
+                let vertices: Vec<_> = points.iter().map(|p| point![p.x, p.y]).collect();
+                let n = vertices.len() as u32;
+                let indices: Vec<_> = (0..n).map(|i| [i, (i + 1) % n]).collect();
+                ColliderBuilder::polyline(vertices, Some(indices))
+            }
+        }
+    }
+
+    pub fn contains(&self, point: Coords) -> bool {
+        match self {
+            Shape::Cuboid {
+                center,
+                half_width,
+                half_height,
+                rotation,
+            } => {
+                // Rotate the point into the cuboid's frame
+                let (dx, dy) = (point.x - center.x, point.y - center.y);
+                let (sin, cos) = (-rotation).sin_cos();
+                let (x, y) = (dx * cos - dy * sin, dx * sin + dy * cos);
+                x.abs() <= *half_width && y.abs() <= *half_height
+            }
+            Shape::Circle { center, radius } => {
+                let (dx, dy) = (point.x - center.x, point.y - center.y);
+                dx * dx + dy * dy <= radius * radius
+            }
+            Shape::Polygon { points } => polygon_contains(points, point),
+        }
+    }
+
+    // Distance from a point to the shape, 0 inside of it
+    pub fn distance(&self, point: Coords) -> f32 {
+        match self {
+            Shape::Cuboid {
+                center,
+                half_width,
+                half_height,
+                rotation,
+            } => {
+                let (dx, dy) = (point.x - center.x, point.y - center.y);
+                let (sin, cos) = (-rotation).sin_cos();
+                let (x, y) = (dx * cos - dy * sin, dx * sin + dy * cos);
+                let (x, y) = (
+                    (x.abs() - half_width).max(0.0),
+                    (y.abs() - half_height).max(0.0),
+                );
+                (x * x + y * y).sqrt()
+            }
+            Shape::Circle { center, radius } => {
+                let (dx, dy) = (point.x - center.x, point.y - center.y);
+                ((dx * dx + dy * dy).sqrt() - radius).max(0.0)
+            }
+            Shape::Polygon { points } => {
+                if polygon_contains(points, point) {
+                    return 0.0;
+                }
+                let n = points.len();
+                (0..n)
+                    .map(|i| segment_distance(points[i], points[(i + 1) % n], point))
+                    .fold(f32::INFINITY, f32::min)
+            }
+        }
+    }
+}
+
+fn segment_distance(a: Coords, b: Coords, point: Coords) -> f32 {
+    let (abx, aby) = (b.x - a.x, b.y - a.y);
+    let length = abx * abx + aby * aby;
+    let t = if length > 0.0 {
+        (((point.x - a.x) * abx + (point.y - a.y) * aby) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (dx, dy) = (point.x - a.x - t * abx, point.y - a.y - t * aby);
+    (dx * dx + dy * dy).sqrt()
+}
+
+fn obstacle(obstacles: &mut [Shape], index: usize) -> Result<&mut Shape, String> {
+    obstacles
+        .get_mut(index)
+        .ok_or_else(|| format!("no obstacle {}", index))
+}
+
+fn spawn_point(spawn_points: &mut [Coords], index: usize) -> Result<&mut Coords, String> {
+    spawn_points
+        .get_mut(index)
+        .ok_or_else(|| format!("no spawn point {}", index))
+}
+
+// Even-odd rule
+fn polygon_contains(points: &[Coords], point: Coords) -> bool {
+    let mut inside = false;
+    let mut j = points.len() - 1;
+    for i in 0..points.len() {
+        let (a, b) = (points[i], points[j]);
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> Map {
+        Map {
+            name: String::from("square"),
+            boundary: vec![
+                Coords { x: 0.0, y: 0.0 },
+                Coords { x: 100.0, y: 0.0 },
+                Coords { x: 100.0, y: 100.0 },
+                Coords { x: 0.0, y: 100.0 },
+            ],
+            obstacles: vec![],
+            spawn_points: vec![Coords { x: 10.0, y: 10.0 }],
+            zones: vec![],
+            seed: None,
+        }
+    }
+
+    #[test]
+    fn edits_change_the_map() {
+        let mut map = square();
+        let circle = Shape::Circle {
+            center: Coords { x: 50.0, y: 50.0 },
+            radius: 5.0,
+        };
+        map.apply(&MapEdit::PlaceObstacle { shape: circle })
+            .unwrap();
+        map.apply(&MapEdit::MoveObstacle {
+            index: 0,
+            to: Coords { x: 70.0, y: 30.0 },
+        })
+        .unwrap();
+        assert_eq!(map.obstacles[0].center(), Coords { x: 70.0, y: 30.0 });
+        map.apply(&MapEdit::PlaceSpawnPoint {
+            at: Coords { x: 90.0, y: 90.0 },
+        })
+        .unwrap();
+        map.apply(&MapEdit::DeleteSpawnPoint { index: 0 }).unwrap();
+        assert_eq!(map.spawn_points, vec![Coords { x: 90.0, y: 90.0 }]);
+        map.apply(&MapEdit::DeleteObstacle { index: 0 }).unwrap();
+        assert!(map.obstacles.is_empty());
+    }
+
+    #[test]
+    fn invalid_edits_leave_the_map_untouched() {
+        let mut map = square();
+        assert!(map.apply(&MapEdit::DeleteObstacle { index: 0 }).is_err());
+        assert!(map
+            .apply(&MapEdit::PlaceObstacle {
+                shape: Shape::Polygon {
+                    points: vec![Coords { x: 1.0, y: 1.0 }, Coords { x: 2.0, y: 2.0 }],
+                },
+            })
+            .is_err());
+        // Covers the spawn point
+        assert!(map
+            .apply(&MapEdit::PlaceObstacle {
+                shape: Shape::Circle {
+                    center: Coords { x: 10.0, y: 10.0 },
+                    radius: 5.0,
+                },
+            })
+            .is_err());
+        assert!(map
+            .apply(&MapEdit::MoveSpawnPoint {
+                index: 0,
+                to: Coords { x: 200.0, y: 10.0 },
+            })
+            .is_err());
+        // Sizes beyond what the physics can handle, 1e39 is read as infinity
+        for radius in [f32::INFINITY, f32::NAN, 1e30] {
+            assert!(map
+                .apply(&MapEdit::PlaceObstacle {
+                    shape: Shape::Circle {
+                        center: Coords { x: 50.0, y: 50.0 },
+                        radius,
+                    },
+                })
+                .is_err());
+        }
+        assert!(map.obstacles.is_empty());
+        assert_eq!(map.spawn_points, vec![Coords { x: 10.0, y: 10.0 }]);
+    }
+
+    #[test]
+    fn polygons_move_by_their_center() {
+        let mut shape = Shape::Polygon {
+            points: vec![
+                Coords { x: 0.0, y: 0.0 },
+                Coords { x: 2.0, y: 0.0 },
+                Coords { x: 1.0, y: 3.0 },
+            ],
+        };
+        shape.move_to(Coords { x: 11.0, y: 11.0 });
+        let Shape::Polygon { points } = &shape else {
+            unreachable!()
+        };
+        assert_eq!(points[0], Coords { x: 10.0, y: 10.0 });
+        assert_eq!(shape.center(), Coords { x: 11.0, y: 11.0 });
+        assert!(shape.contains(Coords { x: 11.0, y: 11.0 }));
+        assert_eq!(shape.distance(Coords { x: 11.0, y: 11.0 }), 0.0);
+    }
+}
@@ -1,52 +1,353 @@
+use crate::components::{ComponentStore, Components, TeamId};
 use crate::custom_ws::{GameInstruction, PhysicsInstruction, Ws};
-use crate::state::InnerState;
+use crate::game_mode::{FlagAction, FlagInfo, GameMode, Kill, TeamScore};
+use crate::map::{Map, MapEdit};
+use crate::state::{Arena, BodyClass, InnerState, Settings};
 use actix::Addr;
 use actix::{Actor, AsyncContext, Context, Handler, Message};
 
+use rand::Rng;
 use rapier2d::prelude::*;
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::f32::consts::PI;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// Bodies of both colliders of every collision that started during a step
+// None for colliders without a body, such as walls
+type Collisions = Vec<(Option<RigidBodyHandle>, Option<RigidBodyHandle>)>;
+
+// Sensor and other collider of every contact with a sensor that started during a step
+type SensorContacts = Vec<(ColliderHandle, ColliderHandle)>;
 
 struct CustomEventHandler {
-    handles_to_decrement_health: Arc<Mutex<Vec<RigidBodyHandle>>>,
+    collisions: Arc<Mutex<Collisions>>,
+    sensors: Arc<Mutex<SensorContacts>>,
 }
 struct CustomPhysicsHooks;
 
-#[derive(Debug, Serialize, Clone, Copy)]
+// Physics steps (and snapshots) per second
+pub const TICK_RATE: u32 = 128;
+
+// Random positions tried when no spawn point from Settings is free
+const SPAWN_CANDIDATES: usize = 32;
+
+// Side of the cells entities are bucketed in for update_interest
+const INTEREST_CELL: Real = 256.0;
+
+// Indices of entities by the grid cell their center is in, rebuilt every tick
+// Only cells with entities in them exist, however far apart the entities are
+struct InterestGrid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+// Stable identifier for players and bullets, never reused while the server runs
+// Also stored in the user_data of the entity's collider, 0 means the collider isn't an entity
+pub type EntityId = u32;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub struct Coords {
     pub x: Real,
     pub y: Real,
 }
 
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum EntityKind {
+    Player,
+    Bullet,
+}
+
+// Static facts about an entity, sent to clients once when it spawns
+#[derive(Debug, Serialize, Clone)]
+pub struct EntityMeta {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    pub username: Option<Arc<str>>,
+    pub color: &'static str,
+}
+
+// Colors handed out to players by id
+const PLAYER_COLORS: [&str; 8] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+];
+const BULLET_COLOR: &str = "grey";
+
+fn player_color(id: EntityId) -> &'static str {
+    PLAYER_COLORS[id as usize % PLAYER_COLORS.len()]
+}
+
+// Only the dynamic state of a player, everything static is in EntityMeta
 #[derive(Debug)]
-pub struct EnemyInfo {
-    pub ws_address: Addr<Ws>,
+pub struct PlayerState {
+    pub id: EntityId,
     pub coords: Coords,
-    pub health: f32,
+    pub velocity: Coords,
     pub dir: f32,
+    pub health: f32,
+    // Sequence number of the last input applied to this player, for client-side reconciliation
+    pub last_input_seq: u32,
+    // Spawn protection is active, see Settings::spawn_protection_ms
+    pub protected: bool,
+    pub team: Option<TeamId>,
+    // Team whose flag the player carries
+    pub flag: Option<TeamId>,
+}
+
+#[derive(Debug)]
+pub struct BulletInfo {
+    pub id: EntityId,
+    pub coords: Coords,
+}
+
+// Everything in the arena at the end of a tick
+// Built once per tick and shared by every connection, which derive their own view from it
+// players and bullets are sorted by id
+#[derive(Debug, Default)]
+pub struct WorldSnapshot {
+    pub players: Vec<PlayerState>,
+    pub bullets: Vec<BulletInfo>,
+    // Entities that appeared or went away since the last tick
+    pub spawned: Vec<EntityMeta>,
+    pub despawned: Vec<EntityId>,
+}
+
+// Every entity that currently exists, sent to a connection when it joins or starts spectating
+// From then on it keeps up through WorldSnapshot::spawned and despawned
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct KnownEntities {
+    pub entities: Vec<EntityMeta>,
+}
+
+// Something that happened during a tick, as opposed to continuous state
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum GameEvent {
+    PlayerJoined {
+        id: EntityId,
+    },
+    PlayerLeft {
+        id: EntityId,
+    },
+    // killer is None if the victim died without being shot by someone else
+    Kill {
+        killer: Option<EntityId>,
+        assists: Vec<EntityId>,
+        victim: EntityId,
+    },
+    // attacker is None for damage that wasn't caused by someone else's bullet
+    Hit {
+        attacker: Option<EntityId>,
+        target: EntityId,
+        damage: f32,
+    },
+    WallBounce {
+        bullet: EntityId,
+        coords: Coords,
+    },
+    // Score of every team, sent when it changes and to players as they spawn
+    TeamScores {
+        scores: Vec<TeamScore>,
+    },
+    // The game mode's round ended, winner is None on a draw
+    RoundOver {
+        winner: Option<TeamId>,
+        scores: Vec<TeamScore>,
+    },
+    // Something happened to a team's flag, by is who took, returned or captured it
+    Flag {
+        team: TeamId,
+        action: FlagAction,
+        by: Option<EntityId>,
+    },
+    // Where every flag is, sent when one moves and to players as they spawn
+    Flags {
+        flags: Vec<FlagInfo>,
+    },
+}
+
+// Who a GameEvent is delivered to
+#[derive(Debug)]
+pub enum Audience {
+    // Every player and spectator
+    Everyone,
+    // Only these players, even if they died this tick
+    Players(Vec<EntityId>),
+    // Players that can see this entity, and spectators
+    Seeing(EntityId),
+}
+
+// Events of the last tick relevant to one connection, sent after its snapshot
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct GameEvents {
+    pub events: Vec<GameEvent>,
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct PhysicsStateResponse {
-    pub my_coords: Coords,
-    pub health: f32,
-    pub enemies: Vec<EnemyInfo>,
-    pub bullets: Vec<Coords>,
+    pub world: Arc<WorldSnapshot>,
+    // Our own player, None when spectating
+    pub me: Option<EntityId>,
+    // Sorted ids of the entities we can see, None to see everything
+    pub visible: Option<Vec<EntityId>>,
+}
+
+// Latest input received from a client
+// Held keys stay held until the client says otherwise
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PlayerInput {
+    pub w: bool,
+    pub a: bool,
+    pub s: bool,
+    pub d: bool,
+    pub dir: f32,
+    pub click: bool,
+    // Increases with every input a client sends, starting from 1
+    pub seq: u32,
 }
 
 pub struct PhysicsPlayerInfo {
+    pub id: EntityId,
+    pub username: Arc<str>,
     pub handle: RigidBodyHandle,
     pub dir: f32,
     pub bullet_cooldown: i32,
+    pub input: PlayerInput,
+    pub last_input_seq: u32,
+    // Size of the client's screen in world units, capped by Settings::view_width and view_height
+    pub viewport: Coords,
+    // Entities this player could see last tick sorted by id, see update_interest
+    pub visible: Vec<EntityId>,
+    pub joined_at: Instant,
+    pub stats: PlayerStats,
+    // Connections whose bullets hit this player and when they last did, oldest first
+    // The last one gets the kill, the others an assist if they're recent enough
+    pub attackers: Vec<(Addr<Ws>, Instant)>,
+    // Team whose flag the player carries, see set_flag
+    pub flag: Option<TeamId>,
+}
+
+pub struct PhysicsBulletInfo {
+    pub id: EntityId,
+    // Ticks since the bullet was fired
+    pub age: u32,
+}
+
+// Running totals for a player, kept across respawns
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PlayerStats {
+    pub kills: u32,
+    pub assists: u32,
+    pub damage_dealt: f32,
+    pub damage_taken: f32,
+    pub shots_fired: u32,
+    pub shots_hit: u32,
 }
 
-#[derive(Message)]
+#[derive(Debug, Serialize)]
+pub struct Attacker {
+    pub id: EntityId,
+    pub username: Arc<str>,
+}
+
+// Sent to a player when they die
+#[derive(Message, Debug, Serialize)]
+#[rtype(result = "()")]
+pub struct GameOver {
+    // None if nobody else hit the player
+    pub killer: Option<Attacker>,
+    // Players other than the killer that recently damaged this one
+    pub assists: Vec<Attacker>,
+    pub time_alive_ms: u64,
+    // Rank by kills among everyone still playing, 1 is best
+    pub placement: u32,
+    pub stats: PlayerStats,
+    // Earliest a Respawn can take effect
+    pub respawn_in_ms: u64,
+}
+
+// Tells every connection that the arena changed, before the first snapshot of the new one
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ArenaChanged {
+    pub map: Arc<Map>,
+    pub settings: Arc<Settings>,
+}
+
+// The map being played was edited, sent to every connection
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct MapEdited {
+    pub map: Arc<Map>,
+}
+
+// Answers to an admin's map edit, successful edits are answered with MapEdited
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct EditRejected {
+    pub reason: String,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct MapSaved {
+    pub name: String,
+}
+
+// Admin command to move on to the next arena of the rotation right away
+#[derive(Message, Debug)]
 #[rtype(result = "()")]
-pub struct GameOver;
+pub struct NextArena;
+
+// Tells a dead player's connection it has a body again
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Respawned;
+
+// A player between dying and respawning, with what carries over to the next life
+pub struct DeadPlayer {
+    // Entity of the body that died, credited for the player's hits until they respawn
+    pub id: EntityId,
+    pub username: Arc<str>,
+    pub stats: PlayerStats,
+    pub viewport: Coords,
+    pub respawn_at: Instant,
+    // Respawn as soon as respawn_at is reached, even without auto_respawn
+    pub requested: bool,
+}
+
+impl InterestGrid {
+    fn new(positions: impl Iterator<Item = Vector<Real>>) -> InterestGrid {
+        let mut cells: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, p) in positions.enumerate() {
+            cells.entry(InterestGrid::cell(p)).or_default().push(i);
+        }
+        InterestGrid { cells }
+    }
+
+    fn cell(p: Vector<Real>) -> (i32, i32) {
+        (
+            (p.x / INTEREST_CELL).floor() as i32,
+            (p.y / INTEREST_CELL).floor() as i32,
+        )
+    }
+
+    // Entities in the cells touching the rectangle of the given half extents around center
+    // Entities are bucketed by their center, so the rectangle is grown by a cell to catch
+    // the edges of those centered just outside it
+    fn around(&self, center: Vector<Real>, half: Vector<Real>) -> impl Iterator<Item = usize> + '_ {
+        let grow = vector![INTEREST_CELL, INTEREST_CELL];
+        let (left, top) = InterestGrid::cell(center - half - grow);
+        let (right, bottom) = InterestGrid::cell(center + half + grow);
+        (top..=bottom)
+            .flat_map(move |row| (left..=right).map(move |column| (column, row)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flat_map(|cell| cell.iter().copied())
+    }
+}
 
 impl EventHandler for CustomEventHandler {
     fn handle_collision_event(
@@ -56,14 +357,22 @@ impl EventHandler for CustomEventHandler {
         event: CollisionEvent,
         _contact_pair: Option<&ContactPair>,
     ) {
-        if let CollisionEvent::Started(handle1, handle2, _flags) = event {
-            let mut v = self.handles_to_decrement_health.lock().unwrap();
-            if let Some(h) = colliders.get(handle1).unwrap().parent() {
-                v.push(h);
-            }
-            if let Some(h) = colliders.get(handle2).unwrap().parent() {
-                v.push(h);
+        if let CollisionEvent::Started(handle1, handle2, flags) = event {
+            // Sensors are no obstacle, touching one isn't a collision
+            if flags.contains(CollisionEventFlags::SENSOR) {
+                let sensor = colliders.get(handle1).is_some_and(|c| c.is_sensor());
+                let (sensor, other) = if sensor {
+                    (handle1, handle2)
+                } else {
+                    (handle2, handle1)
+                };
+                self.sensors.lock().unwrap().push((sensor, other));
+                return;
             }
+            self.collisions.lock().unwrap().push((
+                colliders.get(handle1).unwrap().parent(),
+                colliders.get(handle2).unwrap().parent(),
+            ));
         }
     }
 }
@@ -80,15 +389,46 @@ pub struct PhysicsEngine {
     impulse_joint_set: ImpulseJointSet,
     multibody_joint_set: MultibodyJointSet,
     ccd_solver: CCDSolver,
+    query_pipeline: QueryPipeline,
     _physics_hooks: CustomPhysicsHooks,
     event_handler: CustomEventHandler,
 
     rigid_body_set: RigidBodySet,
     collider_set: ColliderSet,
+    // Walls and obstacles of the map, rebuilt whenever the map is edited
+    wall_handles: Vec<ColliderHandle>,
+    // Players that started touching a sensor during the last step
+    sensor_contacts: Vec<(ColliderHandle, EntityId)>,
 
     player_body_handles: HashMap<Addr<Ws>, PhysicsPlayerInfo>,
-    bullet_handles: HashMap<RigidBodyHandle, u32>,
+    // Connections watching the game without a body
+    spectators: HashSet<Addr<Ws>>,
+    // Players waiting to respawn, they see the game like spectators meanwhile
+    dead_players: HashMap<Addr<Ws>, DeadPlayer>,
+    // Dead players that went on to spectate, their stats and team wait for them to join again
+    benched: HashMap<Addr<Ws>, DeadPlayer>,
+    // Connection of every player body of this arena, kept after the body is gone
+    // Attacks are credited to the connection, so a shooter that died or respawned since still counts
+    connections: HashMap<EntityId, Addr<Ws>>,
+    bullet_handles: HashMap<RigidBodyHandle, PhysicsBulletInfo>,
+    next_entity_id: EntityId,
+    // Collected during a tick and handed out with the next world snapshot
+    spawned: Vec<EntityMeta>,
+    despawned: Vec<EntityId>,
+    events: Vec<(Audience, GameEvent)>,
+    // Health, ownership and the like of every entity, by id
+    components: ComponentStore,
 
+    // Arena being played, and its index in Settings::map_rotation
+    arena: Arena,
+    rotation_index: usize,
+    // When the arena started, it rotates Settings::map_rotation_interval_ms later
+    arena_started: Instant,
+    // Rules of the game, only None while one of its hooks runs, see with_mode
+    mode: Option<Box<dyn GameMode>>,
+    // Players the game mode killed this tick, and whether it ended the round
+    dying: Vec<EntityId>,
+    round_over: bool,
     state: Arc<InnerState>,
 }
 
@@ -104,14 +444,33 @@ impl PhysicsEngine {
             impulse_joint_set: ImpulseJointSet::new(),
             multibody_joint_set: MultibodyJointSet::new(),
             ccd_solver: CCDSolver::new(),
+            query_pipeline: QueryPipeline::new(),
             _physics_hooks: CustomPhysicsHooks {},
             event_handler: CustomEventHandler {
-                handles_to_decrement_health: Arc::from(Mutex::from(vec![])),
+                collisions: Arc::from(Mutex::from(vec![])),
+                sensors: Arc::from(Mutex::from(vec![])),
             },
             rigid_body_set: RigidBodySet::new(),
             collider_set: ColliderSet::new(),
+            wall_handles: Vec::new(),
+            sensor_contacts: vec![],
             player_body_handles: HashMap::new(),
+            spectators: HashSet::new(),
+            dead_players: HashMap::new(),
+            benched: HashMap::new(),
+            connections: HashMap::new(),
             bullet_handles: HashMap::new(),
+            next_entity_id: 1,
+            spawned: vec![],
+            despawned: vec![],
+            events: vec![],
+            components: ComponentStore::new(),
+            mode: Some(state.arena().settings.game_mode.create()),
+            dying: vec![],
+            round_over: false,
+            arena: state.arena(),
+            rotation_index: 0,
+            arena_started: Instant::now(),
             state,
         }
     }
@@ -133,208 +492,1105 @@ impl PhysicsEngine {
         );
     }
 
-    fn apply_force_from_dir(rigid_body: &mut RigidBody, direction: Vector<Real>) {
-        rigid_body.apply_impulse(direction, true);
+    fn new_entity_id(&mut self) -> EntityId {
+        let id = self.next_entity_id;
+        self.next_entity_id += 1;
+        id
     }
 
-    fn decrement_health(&mut self) {
-        let mut v = self
-            .event_handler
-            .handles_to_decrement_health
-            .lock()
-            .unwrap();
-        let damage = self.state.settings.bullet_damage;
-        for handle in v.iter() {
-            if let Some(body) = self.rigid_body_set.get_mut(*handle) {
-                if damage < body.user_data {
-                    body.user_data -= damage;
+    fn player_meta(player: &PhysicsPlayerInfo) -> EntityMeta {
+        EntityMeta {
+            id: player.id,
+            kind: EntityKind::Player,
+            username: Some(player.username.clone()),
+            color: player_color(player.id),
+        }
+    }
+
+    fn bullet_meta(id: EntityId) -> EntityMeta {
+        EntityMeta {
+            id,
+            kind: EntityKind::Bullet,
+            username: None,
+            color: BULLET_COLOR,
+        }
+    }
+
+    // Static info about every entity currently in the arena
+    fn entity_metas(&self) -> Vec<EntityMeta> {
+        self.player_body_handles
+            .values()
+            .map(PhysicsEngine::player_meta)
+            .chain(
+                self.bullet_handles
+                    .values()
+                    .map(|bullet| PhysicsEngine::bullet_meta(bullet.id)),
+            )
+            .collect()
+    }
+
+    // Builds the state of every entity, shared by all the connections this tick
+    // Takes the spawns and despawns collected since the last call
+    pub fn world_snapshot(&mut self) -> WorldSnapshot {
+        let now = Instant::now();
+        let mut players: Vec<_> = self
+            .player_body_handles
+            .values()
+            .map(|player| {
+                let rigid_body = self.rigid_body_set.get(player.handle).unwrap();
+                let t = rigid_body.translation();
+                let v = rigid_body.linvel();
+                PlayerState {
+                    id: player.id,
+                    coords: Coords { x: t.x, y: t.y },
+                    velocity: Coords { x: v.x, y: v.y },
+                    dir: player.dir,
+                    health: self
+                        .components
+                        .get(player.id)
+                        .map_or(0.0, |c| c.health_fraction()),
+                    last_input_seq: player.last_input_seq,
+                    protected: self
+                        .components
+                        .get(player.id)
+                        .is_some_and(|c| c.is_protected(now)),
+                    team: self.components.team(player.id),
+                    flag: player.flag,
+                }
+            })
+            .collect();
+        players.sort_unstable_by_key(|p| p.id);
+
+        let mut bullets: Vec<_> = self
+            .bullet_handles
+            .iter()
+            .map(|(handle, PhysicsBulletInfo { id, .. })| {
+                let t = self.rigid_body_set.get(*handle).unwrap().translation();
+                BulletInfo {
+                    id: *id,
+                    coords: Coords { x: t.x, y: t.y },
+                }
+            })
+            .collect();
+        bullets.sort_unstable_by_key(|b| b.id);
+
+        WorldSnapshot {
+            players,
+            bullets,
+            spawned: std::mem::take(&mut self.spawned),
+            despawned: std::mem::take(&mut self.despawned),
+        }
+    }
+
+    // Works out which entities each player can see
+    // Anything touching the viewport becomes visible, and stays visible until it leaves the viewport
+    // grown by Settings::interest_margin, so that entities on the edge don't flicker in and out
+    // Entities are bucketed on a grid once, so each player only looks at the cells around it
+    fn update_interest(&mut self) {
+        let settings = &self.arena.settings;
+        let margin = settings.interest_margin;
+        // Every entity with the radius of its collider, sorted by id
+        let mut entities: Vec<(EntityId, Vector<Real>, Real)> = self
+            .player_body_handles
+            .values()
+            .map(|player| (player.id, player.handle, settings.ball_size))
+            .chain(
+                self.bullet_handles
+                    .iter()
+                    .map(|(&handle, bullet)| (bullet.id, handle, settings.bullet_size)),
+            )
+            .map(|(id, handle, radius)| (id, *self.rigid_body_set[handle].translation(), radius))
+            .collect();
+        entities.sort_unstable_by_key(|&(id, _, _)| id);
+        let grid = InterestGrid::new(entities.iter().map(|&(_, position, _)| position));
+
+        // Which entities a player sees, by index in entities, so that they come out sorted by id
+        let mut seen = vec![0u64; entities.len().div_ceil(64)];
+        for player in self.player_body_handles.values_mut() {
+            let center = *self.rigid_body_set[player.handle].translation();
+            let half = vector![player.viewport.x / 2.0, player.viewport.y / 2.0];
+            let outer = half + vector![margin, margin];
+            seen.fill(0);
+            for i in grid.around(center, outer) {
+                let (id, position, radius) = entities[i];
+                let offset = (position - center).abs() - vector![radius, radius];
+                let inside = offset.x <= half.x && offset.y <= half.y;
+                let kept = || {
+                    offset.x <= outer.x
+                        && offset.y <= outer.y
+                        && player.visible.binary_search(&id).is_ok()
+                };
+                if inside || kept() {
+                    seen[i / 64] |= 1 << (i % 64);
+                }
+            }
+            player.visible.clear();
+            for (word, &bits) in seen.iter().enumerate() {
+                let mut bits = bits;
+                while bits != 0 {
+                    player
+                        .visible
+                        .push(entities[word * 64 + bits.trailing_zeros() as usize].0);
+                    bits &= bits - 1;
                 }
             }
         }
-        v.clear();
     }
-}
 
-impl Actor for PhysicsEngine {
-    type Context = Context<Self>;
+    // Runs one iteration of the physics engine and sends state data to clients
+    pub fn tick(&mut self) {
+        self.respawn_players();
+        self.apply_inputs();
+        self.step();
+        self.collect_sensor_contacts();
 
-    // Send state every tick
-    fn started(&mut self, ctx: &mut Self::Context) {
-        // Bounding box
-        let (w, h) = (
-            self.state.settings.arena_width,
-            self.state.settings.arena_height,
+        // Decrement bullet cooldowns
+        self.player_body_handles.iter_mut().for_each(
+            |(
+                _,
+                PhysicsPlayerInfo {
+                    bullet_cooldown, ..
+                },
+            )| {
+                *bullet_cooldown -= 1;
+                *bullet_cooldown = 0.max(*bullet_cooldown);
+            },
         );
-        for collider in [
-            ColliderBuilder::cuboid(w, 0.1)
-                .translation(vector![0.0, 0.0])
-                .build(),
-            ColliderBuilder::cuboid(w, 0.1)
-                .translation(vector![0.0, h])
-                .build(),
-            ColliderBuilder::cuboid(w, 0.1).rotation(PI / 2.0).build(),
-            ColliderBuilder::cuboid(w, 0.1)
-                .rotation(PI / 2.0)
-                .translation(vector![w, 0.0])
-                .build(),
-        ] {
-            self.collider_set.insert(collider);
+
+        for bullet in self.bullet_handles.values_mut() {
+            bullet.age += 1;
         }
 
-        // Every 128th of a second, run an iteration of the physics engine and send state data to clients
-        ctx.run_interval(Duration::new(0, 7812500), |s, _| {
+        // Decrement health
+        self.decrement_health();
 
-            s.step();
+        // Game over for whoever the game mode killed
+        self.with_mode(|mode, engine| mode.on_tick(engine));
+        for id in std::mem::take(&mut self.dying) {
+            let address = self
+                .player_body_handles
+                .iter()
+                .find(|(_, player)| player.id == id)
+                .map(|(address, _)| address.clone());
+            if let Some(address) = address {
+                self.kill_player(&address);
+            }
+        }
 
-            // Decrement bullet cooldowns
-            s.player_body_handles.iter_mut().for_each(
-                |(
-                    _,
-                    PhysicsPlayerInfo {
-                        bullet_cooldown, ..
-                    },
-                )| {
-                    *bullet_cooldown -= 1;
-                    *bullet_cooldown = 0.max(*bullet_cooldown);
-                },
+        self.update_interest();
+
+        let world = Arc::new(self.world_snapshot());
+
+        for (address, PhysicsPlayerInfo { id, visible, .. }) in self.player_body_handles.iter() {
+            address.do_send(PhysicsStateResponse {
+                world: world.clone(),
+                me: Some(*id),
+                visible: Some(visible.clone()),
+            });
+        }
+
+        // Spectators see everything
+        for address in self.spectators.iter().chain(self.dead_players.keys()) {
+            address.do_send(PhysicsStateResponse {
+                world: world.clone(),
+                me: None,
+                visible: None,
+            });
+        }
+
+        self.send_events();
+
+        // Skipped or finished arenas get a full interval too
+        let rotation = Duration::from_millis(self.state.settings.map_rotation_interval_ms);
+        let rotation_due = !rotation.is_zero() && self.arena_started.elapsed() >= rotation;
+        if std::mem::take(&mut self.round_over) || rotation_due {
+            self.next_arena();
+        }
+    }
+
+    // Lends the game mode to f along with the engine
+    fn with_mode<R>(&mut self, f: impl FnOnce(&mut dyn GameMode, &mut Self) -> R) -> R {
+        let mut mode = self
+            .mode
+            .take()
+            .expect("game mode hooks must not call each other");
+        let result = f(mode.as_mut(), self);
+        self.mode = Some(mode);
+        result
+    }
+
+    // Settings of the arena being played
+    pub fn settings(&self) -> &Settings {
+        &self.arena.settings
+    }
+
+    pub fn components(&self) -> &ComponentStore {
+        &self.components
+    }
+
+    pub fn components_mut(&mut self) -> &mut ComponentStore {
+        &mut self.components
+    }
+
+    pub fn push_event(&mut self, audience: Audience, event: GameEvent) {
+        self.events.push((audience, event));
+    }
+
+    // Kills a player at the end of the game mode's tick
+    pub fn kill(&mut self, id: EntityId) {
+        if !self.dying.contains(&id) {
+            self.dying.push(id);
+        }
+    }
+
+    // Moves on to the next arena once the current tick has been sent
+    pub fn end_round(&mut self) {
+        self.round_over = true;
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.arena.map
+    }
+
+    pub fn player_position(&self, id: EntityId) -> Option<Coords> {
+        let player = self.player_body_handles.values().find(|p| p.id == id)?;
+        let position = self.rigid_body_set[player.handle].translation();
+        Some(Coords {
+            x: position.x,
+            y: position.y,
+        })
+    }
+
+    // Marks a player as carrying the flag of a team, shown in snapshots
+    pub fn set_flag(&mut self, id: EntityId, flag: Option<TeamId>) {
+        if let Some(player) = self.player_body_handles.values_mut().find(|p| p.id == id) {
+            player.flag = flag;
+        }
+    }
+
+    // Adds a round area players can walk through, touching it shows up in sensor_contacts
+    // Sensors are gone when the arena changes
+    pub fn add_sensor(&mut self, center: Coords, radius: f32) -> ColliderHandle {
+        let collider = ColliderBuilder::ball(radius)
+            .translation(vector![center.x, center.y])
+            .sensor(true)
+            .collision_groups(InteractionGroups::new(
+                u32::MAX,
+                1 << BodyClass::Player as u32,
+            ))
+            .active_events(ActiveEvents::COLLISION_EVENTS)
+            .build();
+        self.collider_set.insert(collider)
+    }
+
+    pub fn remove_sensor(&mut self, handle: ColliderHandle) {
+        self.collider_set.remove(
+            handle,
+            &mut self.island_manager,
+            &mut self.rigid_body_set,
+            true,
+        );
+    }
+
+    // Sensors players started touching during this tick's step, and who touched them
+    pub fn sensor_contacts(&self) -> Vec<(ColliderHandle, EntityId)> {
+        self.sensor_contacts.clone()
+    }
+
+    fn collect_sensor_contacts(&mut self) {
+        let contacts = std::mem::take(&mut *self.event_handler.sensors.lock().unwrap());
+        self.sensor_contacts = contacts
+            .into_iter()
+            .filter(|(sensor, _)| self.collider_set.contains(*sensor))
+            .filter_map(|(sensor, other)| {
+                let id = self.collider_set.get(other)?.user_data as EntityId;
+                // Only players, bullets don't trigger sensors
+                self.player_body_handles
+                    .values()
+                    .any(|p| p.id == id)
+                    .then_some((sensor, id))
+            })
+            .collect();
+    }
+
+    pub fn remove_bullets_older_than(&mut self, ticks: u32) {
+        let old: Vec<_> = self
+            .bullet_handles
+            .iter()
+            .filter(|(_, bullet)| bullet.age > ticks)
+            .map(|(&handle, _)| handle)
+            .collect();
+        for handle in old {
+            self.rigid_body_set.remove(
+                handle,
+                &mut self.island_manager,
+                &mut self.collider_set,
+                &mut self.impulse_joint_set,
+                &mut self.multibody_joint_set,
+                true,
             );
+            if let Some(bullet) = self.bullet_handles.remove(&handle) {
+                self.components.remove(bullet.id);
+                self.despawned.push(bullet.id);
+            }
+        }
+    }
 
-            // Decrement health
-            s.decrement_health();
+    // Hands out the events collected since the last tick to whoever they concern
+    fn send_events(&mut self) {
+        if self.events.is_empty() {
+            return;
+        }
+        let events = std::mem::take(&mut self.events);
 
+        for (address, PhysicsPlayerInfo { id, visible, .. }) in self.player_body_handles.iter() {
+            let mine: Vec<_> = events
+                .iter()
+                .filter(|(audience, _)| match audience {
+                    Audience::Everyone => true,
+                    Audience::Players(ids) => ids.contains(id),
+                    Audience::Seeing(id) => visible.binary_search(id).is_ok(),
+                })
+                .map(|(_, event)| event.clone())
+                .collect();
+            if !mine.is_empty() {
+                address.do_send(GameEvents { events: mine });
+            }
+        }
 
-            let bullets_to_delete: Vec<_> = s
-                .bullet_handles
-                .iter_mut()
-                .map(|(handle, counter)| {
-                    *counter += 1;
-                    (handle, counter)
+        // Dead players also hear what happened to their last body, such as the hit that killed it
+        for (address, DeadPlayer { id, .. }) in self.dead_players.iter() {
+            let mine: Vec<_> = events
+                .iter()
+                .filter(|(audience, _)| match audience {
+                    Audience::Players(ids) => ids.contains(id),
+                    _ => true,
                 })
-                .filter(|(_handle, counter)| **counter > 500)
-                .map(|(a, b)| (*a, *b))
+                .map(|(_, event)| event.clone())
                 .collect();
+            if !mine.is_empty() {
+                address.do_send(GameEvents { events: mine });
+            }
+        }
+
+        let watched: Vec<_> = events
+            .into_iter()
+            .filter(|(audience, _)| !matches!(audience, Audience::Players(_)))
+            .map(|(_, event)| event)
+            .collect();
+        if !watched.is_empty() {
+            for address in self.spectators.iter() {
+                address.do_send(GameEvents {
+                    events: watched.clone(),
+                });
+            }
+        }
+    }
+
+    // Collision groups for a class of collider, following Settings::collision_rules
+    fn collision_groups(&self, class: BodyClass) -> InteractionGroups {
+        let group = |class: BodyClass| 1 << class as u32;
+        let filter = BodyClass::ALL
+            .into_iter()
+            .filter(|&other| {
+                self.arena
+                    .settings
+                    .collision_rule(class, other)
+                    .is_some_and(|rule| rule.collide)
+            })
+            .fold(0, |filter, other| filter | group(other));
+        InteractionGroups::new(group(class), filter)
+    }
 
+    // Gives a new player a body in the arena
+    // A player can only have one body, joining twice does nothing
+    pub fn join_game(&mut self, address: Addr<Ws>, username: String) {
+        if self.player_body_handles.contains_key(&address) {
+            return;
+        }
+        // Dead players wait for their countdown like with Respawn, so do benched ones
+        if let Some(dead) = self.benched.remove(&address) {
+            self.spectators.remove(&address);
+            self.dead_players.insert(address.clone(), dead);
+        }
+        if let Some(dead) = self.dead_players.get_mut(&address) {
+            dead.requested = true;
+            return;
+        }
+        let viewport = Coords {
+            x: self.arena.settings.view_width,
+            y: self.arena.settings.view_height,
+        };
+        self.with_mode(|mode, engine| mode.on_join(engine, &address));
+        self.spawn_player(
+            address.clone(),
+            Arc::from(username),
+            PlayerStats::default(),
+            viewport,
+        );
+        let id = self.player_body_handles[&address].id;
+        self.events
+            .push((Audience::Everyone, GameEvent::PlayerJoined { id }));
+
+        // The new player needs to hear about everything, including itself, right away
+        address.do_send(KnownEntities {
+            entities: self.entity_metas(),
+        });
+    }
+
+    // Brings a dead player back if they asked to, or automatically, once their countdown is over
+    fn respawn_players(&mut self) {
+        let now = Instant::now();
+        let auto_respawn = self.arena.settings.auto_respawn;
+        let due: Vec<_> = self
+            .dead_players
+            .iter()
+            .filter(|(_, dead)| now >= dead.respawn_at && (dead.requested || auto_respawn))
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in due {
+            let dead = self.dead_players.remove(&address).unwrap();
+            self.spawn_player(address.clone(), dead.username, dead.stats, dead.viewport);
+            address.do_send(Respawned);
+        }
+    }
+
+    // Moves on to the next arena of the rotation
+    // Arenas that fail to load are skipped, to be tried again on the next rotation
+    pub fn next_arena(&mut self) {
+        let rotation = &self.state.settings.map_rotation;
+        let index = (self.rotation_index + 1) % rotation.len();
+        self.rotation_index = index;
+        match Arena::load(&self.state.settings, &rotation[index]) {
+            Ok(arena) => self.load_arena(arena),
+            Err(reason) => println!("Skipping map {:?}: {}", rotation[index].map, reason),
+        }
+    }
+
+    // Tears down the physics world and builds the arena from scratch
+    // Everyone stays connected, and every player, dead or alive, starts over in the new arena
+    fn load_arena(&mut self, arena: Arena) {
+        self.with_mode(|mode, engine| mode.on_round_end(engine));
+        // Whatever happened in the old arena goes out before ArenaChanged
+        self.send_events();
+        self.dying.clear();
+        self.round_over = false;
+
+        let players: Vec<_> = self
+            .player_body_handles
+            .drain()
+            .map(|(address, player)| {
+                self.despawned.push(player.id);
+                (address, player.username, player.viewport)
+            })
+            .chain(
+                self.dead_players
+                    .drain()
+                    .map(|(address, dead)| (address, dead.username, dead.viewport)),
+            )
+            .collect();
+        // Benched players stay spectators, stats and teams start over with the new arena anyway
+        self.benched.clear();
+        self.despawned
+            .extend(self.bullet_handles.drain().map(|(_, bullet)| bullet.id));
+        self.components = ComponentStore::new();
+        self.connections.clear();
+        self.event_handler.collisions.lock().unwrap().clear();
+        self.event_handler.sensors.lock().unwrap().clear();
+        self.sensor_contacts.clear();
+
+        self.island_manager = IslandManager::new();
+        self.broad_phase = BroadPhase::new();
+        self.narrow_phase = NarrowPhase::new();
+        self.impulse_joint_set = ImpulseJointSet::new();
+        self.multibody_joint_set = MultibodyJointSet::new();
+        self.ccd_solver = CCDSolver::new();
+        self.query_pipeline = QueryPipeline::new();
+        self.rigid_body_set = RigidBodySet::new();
+        self.collider_set = ColliderSet::new();
+        self.wall_handles.clear();
+
+        *self.state.arena.write().unwrap() = arena.clone();
+        self.mode = Some(arena.settings.game_mode.create());
+        self.arena = arena;
+        self.arena_started = Instant::now();
+        self.build_arena();
+
+        // Connections that haven't joined yet need the new map too
+        for player in self.state.connected_players.iter() {
+            player.key().do_send(ArenaChanged {
+                map: self.arena.map.clone(),
+                settings: self.arena.settings.clone(),
+            });
+        }
+        for (address, username, viewport) in players {
+            self.with_mode(|mode, engine| mode.on_join(engine, &address));
+            self.spawn_player(address.clone(), username, PlayerStats::default(), viewport);
+            address.do_send(Respawned);
+        }
+    }
+
+    // Builds the walls and obstacles of the map
+    // Obstacles are walls too, as far as collisions are concerned
+    fn build_arena(&mut self) {
+        let groups = self.collision_groups(BodyClass::Wall);
+        let map = self.arena.map.clone();
+        let obstacles = map.obstacles.iter().map(|shape| shape.collider());
+        for collider in map.walls().into_iter().chain(obstacles) {
+            let handle = self
+                .collider_set
+                .insert(collider.collision_groups(groups).build());
+            self.wall_handles.push(handle);
+        }
+    }
+
+    // Applies an admin's edit to the map being played and shows it to everyone
+    // The edited map only lasts until the next rotation unless it is saved
+    fn edit_map(&mut self, edit: MapEdit, admin: Addr<Ws>) {
+        if let MapEdit::Save { name } = &edit {
+            match self.arena.map.save(name) {
+                Ok(()) => admin.do_send(MapSaved { name: name.clone() }),
+                Err(reason) => admin.do_send(EditRejected { reason }),
+            }
+            return;
+        }
+        if let Err(reason) = Arc::make_mut(&mut self.arena.map).apply(&edit) {
+            admin.do_send(EditRejected { reason });
+            return;
+        }
+
+        for handle in self.wall_handles.drain(..) {
+            self.collider_set.remove(
+                handle,
+                &mut self.island_manager,
+                &mut self.rigid_body_set,
+                true,
+            );
+        }
+        self.build_arena();
+        *self.state.arena.write().unwrap() = self.arena.clone();
+
+        for player in self.state.connected_players.iter() {
+            player.key().do_send(MapEdited {
+                map: self.arena.map.clone(),
+            });
+        }
+    }
+
+    // Picks where to spawn a player: the free spawn point of the map furthest from
+    // every other player and bullet, or failing that the best of some random free positions
+    fn spawn_point(&mut self) -> Vector<Real> {
+        // Players spawned earlier in the same tick have to be taken into account
+        self.query_pipeline.update(
+            &self.island_manager,
+            &self.rigid_body_set,
+            &self.collider_set,
+        );
+        let settings = &self.arena.settings;
+        let others: Vec<Vector<Real>> = self
+            .player_body_handles
+            .values()
+            .map(|player| player.handle)
+            .chain(self.bullet_handles.keys().copied())
+            .map(|handle| *self.rigid_body_set[handle].translation())
+            .collect();
+
+        // A spot is free if a ball twice the size of a player fits there without touching anything
+        // Sensors can be walked through, so they don't count
+        let clearance = Ball::new(settings.ball_size * 2.0);
+        let solid = |handle: ColliderHandle| !self.collider_set[handle].is_sensor();
+        let is_free = |spot: &Vector<Real>| {
+            let mut free = true;
+            self.query_pipeline.intersections_with_shape(
+                &self.collider_set,
+                &Isometry::translation(spot.x, spot.y),
+                &clearance,
+                InteractionGroups::all(),
+                Some(&solid),
+                |_| {
+                    free = false;
+                    false
+                },
+            );
+            free
+        };
+        let furthest = |spots: Vec<Vector<Real>>| {
+            spots
+                .into_iter()
+                .filter(|spot| is_free(spot))
+                .map(|spot| {
+                    let distance = others
+                        .iter()
+                        .map(|other| (other - spot).norm())
+                        .fold(f32::INFINITY, f32::min);
+                    (spot, distance)
+                })
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(spot, _)| spot)
+        };
+
+        let map = &self.arena.map;
+        let spawn_points = map
+            .spawn_points
+            .iter()
+            .filter(|&&c| map.contains(c))
+            .map(|c| vector![c.x, c.y])
+            .collect();
+        let (min, max) = map.bounds();
+        let mut rng = rand::thread_rng();
+        let random: Vec<_> = (0..SPAWN_CANDIDATES)
+            .map(|_| Coords {
+                x: rng.gen_range(min.x..max.x.max(min.x + 1.0)),
+                y: rng.gen_range(min.y..max.y.max(min.y + 1.0)),
+            })
+            .filter(|&c| map.contains(c))
+            .map(|c| vector![c.x, c.y])
+            .collect();
+        let center = map.center();
+        furthest(spawn_points)
+            .or_else(|| furthest(random))
+            .unwrap_or(vector![center.x, center.y])
+    }
+
+    // Creates the body of a player, everyone hears about it with the next tick
+    fn spawn_player(
+        &mut self,
+        address: Addr<Ws>,
+        username: Arc<str>,
+        stats: PlayerStats,
+        viewport: Coords,
+    ) {
+        let rigid_body = RigidBodyBuilder::new(RigidBodyType::Dynamic)
+            .translation(self.spawn_point())
+            .linear_damping(self.arena.settings.damping)
+            .ccd_enabled(true)
+            .build();
+        let handle = self.rigid_body_set.insert(rigid_body);
+        let id = self.new_entity_id();
+        self.connections.insert(id, address.clone());
+        // Whoever has a body plays, even if they asked to spectate before respawning
+        self.spectators.remove(&address);
+        let protection = Duration::from_millis(self.arena.settings.spawn_protection_ms);
+        self.components.insert(
+            id,
+            Components {
+                protected_until: Some(Instant::now() + protection),
+                ..Components::player(self.arena.settings.max_health, self.arena.settings.armor)
+            },
+        );
+        self.player_body_handles.insert(
+            address.clone(),
+            PhysicsPlayerInfo {
+                id,
+                username,
+                handle,
+                dir: 0.0,
+                bullet_cooldown: 0,
+                input: PlayerInput::default(),
+                last_input_seq: 0,
+                viewport,
+                visible: vec![],
+                joined_at: Instant::now(),
+                stats,
+                attackers: vec![],
+                flag: None,
+            },
+        );
+        let collider = ColliderBuilder::ball(self.arena.settings.ball_size)
+            .density(1.0)
+            .restitution(0.7)
+            .collision_groups(self.collision_groups(BodyClass::Player))
+            .active_events(ActiveEvents::COLLISION_EVENTS)
+            .user_data(id as u128)
+            .build();
+        self.collider_set
+            .insert_with_parent(collider, handle, &mut self.rigid_body_set);
+
+        let meta = PhysicsEngine::player_meta(&self.player_body_handles[&address]);
+        self.spawned.push(meta);
+        self.with_mode(|mode, engine| mode.on_spawn(engine, &address, id));
+    }
+
+    // Remembers the latest input of a player, it is applied once per tick in apply_inputs
+    // The player may have died (or never joined) while the input was in flight
+    // Client input must never take down the physics engine, so it is just dropped
+    pub fn set_input(&mut self, address: &Addr<Ws>, input: PlayerInput) {
+        // Ws rejects these already, but a non-finite direction must never reach rapier
+        if !input.dir.is_finite() {
+            return;
+        }
+        if let Some(player) = self.player_body_handles.get_mut(address) {
+            // Inputs that arrive out of order are stale
+            if input.seq > player.input.seq {
+                player.input = input;
+            }
+        }
+    }
+
+    fn apply_force_from_dir(rigid_body: &mut RigidBody, direction: Vector<Real>) {
+        rigid_body.apply_impulse(direction, true);
+    }
+
+    // Applies every player's latest input exactly once
+    // Called once per tick, so movement speed doesn't depend on how often clients send input
+    fn apply_inputs(&mut self) {
+        let force: f32 = self.arena.settings.impulse_force;
+        let mut shooters = vec![];
+        for PhysicsPlayerInfo {
+            id,
+            handle,
+            dir,
+            bullet_cooldown,
+            input,
+            last_input_seq,
+            stats,
+            ..
+        } in self.player_body_handles.values_mut()
+        {
+            let rigid_body = self.rigid_body_set.get_mut(*handle).unwrap();
+            *dir = input.dir;
+            *last_input_seq = input.seq;
+
+            if input.w {
+                PhysicsEngine::apply_force_from_dir(rigid_body, vector![0.0, -force])
+            }
+            if input.a {
+                PhysicsEngine::apply_force_from_dir(rigid_body, vector![-force, 0.0])
+            }
+            if input.s {
+                PhysicsEngine::apply_force_from_dir(rigid_body, vector![0.0, force])
+            }
+            if input.d {
+                PhysicsEngine::apply_force_from_dir(rigid_body, vector![force, 0.0])
+            }
 
-            let mut dead_players: Vec<(Addr<Ws>, RigidBodyHandle)> = vec![];
+            if input.click && *bullet_cooldown <= 0 {
+                *bullet_cooldown = 25;
+                stats.shots_fired += 1;
+                shooters.push((*id, *handle, input.dir));
+            }
+        }
+
+        for (id, handle, dir) in shooters {
+            self.fire_bullet(id, handle, dir);
+        }
+    }
+
+    // Spawns a bullet in front of the shooter and pushes the shooter back
+    // Firing ends the shooter's spawn protection
+    fn fire_bullet(&mut self, shooter: EntityId, shooter_handle: RigidBodyHandle, dir: f32) {
+        if let Some(components) = self.components.get_mut(shooter) {
+            components.protected_until = None;
+        }
+        let dir = dir + PI / 2.0;
+        let bullet_speed = self.arena.settings.bullet_speed;
+        let unit_velocity = vector![dir.cos(), dir.sin()];
+        let rigid_body = self.rigid_body_set.get_mut(shooter_handle).unwrap();
+        let trans = *rigid_body.translation();
+        PhysicsEngine::apply_force_from_dir(
+            rigid_body,
+            unit_velocity * self.arena.settings.impulse_force * -10.0,
+        );
+        let rigid_body = RigidBodyBuilder::new(RigidBodyType::Dynamic)
+            .translation(vector![trans.x, trans.y] + unit_velocity * 30.0)
+            .linear_damping(0.25)
+            .ccd_enabled(true)
+            .linvel(unit_velocity * bullet_speed)
+            .build();
+        let handle = self.rigid_body_set.insert(rigid_body);
+        let id = self.new_entity_id();
+        let collider = ColliderBuilder::ball(self.arena.settings.bullet_size)
+            .density(1.0)
+            .restitution(0.93)
+            .collision_groups(self.collision_groups(BodyClass::Bullet))
+            .active_events(ActiveEvents::COLLISION_EVENTS)
+            .user_data(id as u128)
+            .build();
+        self.collider_set
+            .insert_with_parent(collider, handle, &mut self.rigid_body_set);
+        self.bullet_handles
+            .insert(handle, PhysicsBulletInfo { id, age: 0 });
+        self.components.insert(id, Components::bullet(shooter));
+        self.spawned.push(PhysicsEngine::bullet_meta(id));
+    }
+
+    // Damages every player that collided with something during the last step
+    // Hits by someone else's bullet are credited to the shooter
+    fn decrement_health(&mut self) {
+        let collisions = std::mem::take(&mut *self.event_handler.collisions.lock().unwrap());
+        if collisions.is_empty() {
+            return;
+        }
+        let players: HashMap<RigidBodyHandle, Addr<Ws>> = self
+            .player_body_handles
+            .iter()
+            .map(|(address, player)| (player.handle, address.clone()))
+            .collect();
+        let now = Instant::now();
+        let class_of = |handle: Option<RigidBodyHandle>, bullets: &HashMap<_, _>| match handle {
+            None => Some(BodyClass::Wall),
+            Some(h) if players.contains_key(&h) => Some(BodyClass::Player),
+            Some(h) if bullets.contains_key(&h) => Some(BodyClass::Bullet),
+            // Removed since the collision started
+            Some(_) => None,
+        };
 
-            for (address, PhysicsPlayerInfo { handle, .. }) in s.player_body_handles.iter() {
-                let rigid_body = s.rigid_body_set.get_mut(*handle).unwrap();
-                let trans = rigid_body.translation();
+        for (a, b) in collisions {
+            // Walls are the only colliders without a body
+            match (a, b) {
+                (Some(h), None) | (None, Some(h)) if self.bullet_handles.contains_key(&h) => {
+                    let bullet = self.bullet_handles[&h].id;
+                    let position = self.rigid_body_set[h].translation();
+                    self.events.push((
+                        Audience::Seeing(bullet),
+                        GameEvent::WallBounce {
+                            bullet,
+                            coords: Coords {
+                                x: position.x,
+                                y: position.y,
+                            },
+                        },
+                    ));
+                }
+                _ => {}
+            }
 
-                // Game over
-                if rigid_body.user_data <= 5000 {
-                    address.do_send(GameOver {});
-                    dead_players.push((address.clone(), *handle));
+            for (target, other) in [(a, b), (b, a)] {
+                let address = match target.and_then(|h| players.get(&h)) {
+                    Some(address) => address,
+                    None => continue,
+                };
+                let damage = match class_of(other, &self.bullet_handles)
+                    .and_then(|other| self.arena.settings.collision_rule(BodyClass::Player, other))
+                {
+                    Some(rule) if rule.damage > 0.0 => rule.damage,
+                    _ => continue,
+                };
+                let shooter = other
+                    .and_then(|h| self.bullet_handles.get(&h))
+                    .and_then(|bullet| self.components.owner(bullet.id));
+                // Bullets outlive the body that fired them, the hit goes to the shooter's current one
+                let attacker = shooter
+                    .and_then(|shooter| self.connections.get(&shooter))
+                    .cloned();
+                let shooter = attacker
+                    .as_ref()
+                    .and_then(|attacker| self.credited(attacker))
+                    .map(|(id, _, _)| id)
+                    .or(shooter);
+
+                let victim = self.player_body_handles[address].id;
+                if self
+                    .components
+                    .get(victim)
+                    .is_none_or(|components| components.is_protected(now))
+                {
                     continue;
                 }
+                let damage =
+                    self.with_mode(|mode, engine| mode.on_damage(engine, shooter, victim, damage));
+                if damage <= 0.0 {
+                    continue;
+                }
+                let damage = match self.components.get_mut(victim) {
+                    Some(components) => components.damage(damage),
+                    None => continue,
+                };
+                let victim = self.player_body_handles.get_mut(address).unwrap();
+                if damage <= 0.0 {
+                    continue;
+                }
+                victim.stats.damage_taken += damage;
 
-                let r = PhysicsStateResponse {
-                    my_coords: Coords {
-                        x: trans.x,
-                        y: trans.y,
+                let shooter = shooter.filter(|&shooter| shooter != victim.id);
+                self.events.push((
+                    Audience::Players(shooter.into_iter().chain([victim.id]).collect()),
+                    GameEvent::Hit {
+                        attacker: shooter,
+                        target: victim.id,
+                        damage,
                     },
-                    health: health_convert(rigid_body.user_data),
-                    // Iterate through all the players and register them as enemies, exluding our current address
-                    enemies: (s
-                        .player_body_handles
-                        .iter()
-                        .filter(|(inner_address, _)| *inner_address != address)
-                        .map(|(inner_address, PhysicsPlayerInfo { handle, dir, .. })| {
-                            let rigid_body = s.rigid_body_set.get_mut(*handle).unwrap();
-                            let t = rigid_body.translation();
-                            EnemyInfo {
-                                coords: Coords { x: t.x, y: t.y },
-                                health: health_convert(rigid_body.user_data),
-                                ws_address: inner_address.clone(),
-                                dir: *dir,
-                            }
-                        })
-                        .collect()),
-                    bullets: s
-                        .bullet_handles
-                        .iter_mut()
-                        .map(|(handle, _counter)| {
-                            let t = s.rigid_body_set.get_mut(*handle).unwrap().translation();
-                            let c = Coords { x: t.x, y: t.y };
-                            c
-                        })
-                        .collect(),
+                ));
+                let attacker = match attacker.filter(|attacker| attacker != address) {
+                    Some(attacker) => attacker,
+                    None => continue,
                 };
-                address.do_send(r);
-            }
-            
-            // DELETION STAGE
-
-
-            // delete bullets that have timed out
-            for (handle, _) in bullets_to_delete {
-                s.rigid_body_set.remove(
-                    handle,
-                    &mut s.island_manager,
-                    &mut s.collider_set,
-                    &mut s.impulse_joint_set,
-                    &mut s.multibody_joint_set,
-                    true,
-                );
-                s.bullet_handles.remove(&handle);
+                victim.attackers.retain(|(a, _)| *a != attacker);
+                victim.attackers.push((attacker.clone(), Instant::now()));
+                if let Some((_, _, stats)) = self.credited(&attacker) {
+                    stats.damage_dealt += damage;
+                    stats.shots_hit += 1;
+                }
             }
+        }
+    }
+
+    // Body a connection that is still playing has now, or had last if it is dead or benched,
+    // with the username and stats credit for its attacks goes to
+    fn credited(&mut self, address: &Addr<Ws>) -> Option<(EntityId, Arc<str>, &mut PlayerStats)> {
+        if let Some(player) = self.player_body_handles.get_mut(address) {
+            return Some((player.id, player.username.clone(), &mut player.stats));
+        }
+        let dead = match self.dead_players.get_mut(address) {
+            Some(dead) => dead,
+            None => self.benched.get_mut(address)?,
+        };
+        Some((dead.id, dead.username.clone(), &mut dead.stats))
+    }
+
+    // Removes a dead player from the arena and tells them how they did
+    fn kill_player(&mut self, address: &Addr<Ws>) {
+        let player = match self.player_body_handles.remove(address) {
+            Some(player) => player,
+            None => return,
+        };
+        let position = self.rigid_body_set[player.handle].translation();
+        let coords = Coords {
+            x: position.x,
+            y: position.y,
+        };
+        self.rigid_body_set.remove(
+            player.handle,
+            &mut self.island_manager,
+            &mut self.collider_set,
+            &mut self.impulse_joint_set,
+            &mut self.multibody_joint_set,
+            true,
+        );
+        self.components.remove(player.id);
+        self.despawned.push(player.id);
 
-            // Delete players that have died
-            for (address, handle) in dead_players.iter() {
-                s.rigid_body_set.remove(
-                    *handle,
-                    &mut s.island_manager,
-                    &mut s.collider_set,
-                    &mut s.impulse_joint_set,
-                    &mut s.multibody_joint_set,
-                    true,
-                );
-                s.player_body_handles.remove(address);
+        // The most recent attacker gets the kill, even if they died meanwhile
+        // The others get an assist if they hit recently enough, and if the most recent one left,
+        // the next one within the window gets the kill instead
+        let window = Duration::from_millis(self.arena.settings.assist_window_ms);
+        let mut credited = vec![];
+        for (n, (attacker, at)) in player.attackers.iter().rev().enumerate() {
+            if n > 0 && at.elapsed() > window {
+                continue;
             }
+            if let Some((id, username, stats)) = self.credited(attacker) {
+                if credited.is_empty() {
+                    stats.kills += 1;
+                } else {
+                    stats.assists += 1;
+                }
+                credited.push(Attacker { id, username });
+            }
+        }
+        let mut credited = credited.into_iter();
+        let killer = credited.next();
+        let assists: Vec<_> = credited.collect();
+        let kill = Kill {
+            victim: player.id,
+            coords,
+            killer: killer.as_ref().map(|killer| killer.id),
+            assists: assists.iter().map(|assist| assist.id).collect(),
+        };
+        self.with_mode(|mode, engine| mode.on_kill(engine, &kill));
+        self.events.push((
+            Audience::Everyone,
+            GameEvent::Kill {
+                killer: kill.killer,
+                assists: kill.assists,
+                victim: kill.victim,
+            },
+        ));
+        let placement = 1 + self
+            .player_body_handles
+            .values()
+            .map(|p| &p.stats)
+            .chain(self.dead_players.values().map(|dead| &dead.stats))
+            .chain(self.benched.values().map(|dead| &dead.stats))
+            .filter(|stats| stats.kills > player.stats.kills)
+            .count() as u32;
+
+        let respawn_delay = Duration::from_millis(self.arena.settings.respawn_delay_ms);
+        self.dead_players.insert(
+            address.clone(),
+            DeadPlayer {
+                id: player.id,
+                username: player.username,
+                stats: player.stats.clone(),
+                viewport: player.viewport,
+                respawn_at: Instant::now() + respawn_delay,
+                requested: false,
+            },
+        );
+        address.do_send(GameOver {
+            killer,
+            assists,
+            time_alive_ms: player.joined_at.elapsed().as_millis() as u64,
+            placement,
+            stats: player.stats,
+            respawn_in_ms: respawn_delay.as_millis() as u64,
         });
     }
 }
 
+impl Actor for PhysicsEngine {
+    type Context = Context<Self>;
+
+    // Send state every tick
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.build_arena();
+
+        // Every 128th of a second, run an iteration of the physics engine and send state data to clients
+        let tick = Duration::from_nanos(1_000_000_000 / TICK_RATE as u64);
+        ctx.run_interval(tick, |s, _| s.tick());
+    }
+}
+
 impl Handler<PhysicsInstruction> for PhysicsEngine {
     type Result = ();
 
     fn handle(&mut self, msg: PhysicsInstruction, _ctx: &mut Self::Context) -> Self::Result {
         match msg.game_instruction {
-            // Register player body to rigid_body_set
-            GameInstruction::JoinGame => {
-                let mut rigid_body = RigidBodyBuilder::new(RigidBodyType::Dynamic)
-                    .translation(vector![100.0, 100.0])
-                    .linear_damping(self.state.settings.damping)
-                    .ccd_enabled(true)
-                    .build();
-                rigid_body.user_data = 10000;
-                let handle = self.rigid_body_set.insert(rigid_body);
-                self.player_body_handles.insert(
-                    msg.sent_from,
-                    PhysicsPlayerInfo {
-                        handle,
-                        dir: 0.0,
-                        bullet_cooldown: 0,
-                    },
-                );
-                let collider = ColliderBuilder::ball(self.state.settings.ball_size)
-                    .density(1.0)
-                    .restitution(0.7)
-                    .active_events(ActiveEvents::COLLISION_EVENTS)
-                    .build();
-                self.collider_set
-                    .insert_with_parent(collider, handle, &mut self.rigid_body_set);
+            GameInstruction::JoinGame { username } => self.join_game(msg.sent_from, username),
+            GameInstruction::Viewport { width, height } => {
+                let viewport = Coords {
+                    x: width.clamp(0.0, self.arena.settings.view_width),
+                    y: height.clamp(0.0, self.arena.settings.view_height),
+                };
+                if let Some(player) = self.player_body_handles.get_mut(&msg.sent_from) {
+                    player.viewport = viewport;
+                } else if let Some(dead) = self
+                    .dead_players
+                    .get_mut(&msg.sent_from)
+                    .or_else(|| self.benched.get_mut(&msg.sent_from))
+                {
+                    dead.viewport = viewport;
+                }
+            }
+            GameInstruction::EditMap(edit) => self.edit_map(edit, msg.sent_from),
+            GameInstruction::Spectate => {
+                // Respawned while the request was in flight, Ws hears about it with Respawned
+                if self.player_body_handles.contains_key(&msg.sent_from) {
+                    return;
+                }
+                if let Some(dead) = self.dead_players.remove(&msg.sent_from) {
+                    self.benched.insert(msg.sent_from.clone(), dead);
+                }
+                msg.sent_from.do_send(KnownEntities {
+                    entities: self.entity_metas(),
+                });
+                self.spectators.insert(msg.sent_from);
+            }
+            GameInstruction::Respawn => {
+                if let Some(dead) = self.dead_players.get_mut(&msg.sent_from) {
+                    dead.requested = true;
+                }
             }
             GameInstruction::ExitGame => {
-                if let Some(PhysicsPlayerInfo {
-                    handle,
-                    dir: _mut_dir,
-                    bullet_cooldown: _,
-                    ..
-                }) = self.player_body_handles.get_mut(&msg.sent_from)
+                self.spectators.remove(&msg.sent_from);
+                let was_playing = self.dead_players.remove(&msg.sent_from).is_some()
+                    || self.benched.remove(&msg.sent_from).is_some()
+                    || self.player_body_handles.contains_key(&msg.sent_from);
+                if let Some(&PhysicsPlayerInfo { id, handle, .. }) =
+                    self.player_body_handles.get(&msg.sent_from)
                 {
                     self.rigid_body_set.remove(
-                        *handle,
+                        handle,
                         &mut self.island_manager,
                         &mut self.collider_set,
                         &mut self.impulse_joint_set,
@@ -342,6 +1598,13 @@ impl Handler<PhysicsInstruction> for PhysicsEngine {
                         true,
                     );
                     self.player_body_handles.remove(&msg.sent_from);
+                    self.components.remove(id);
+                    self.despawned.push(id);
+                    self.events
+                        .push((Audience::Everyone, GameEvent::PlayerLeft { id }));
+                }
+                if was_playing {
+                    self.with_mode(|mode, engine| mode.on_leave(engine, &msg.sent_from));
                 }
             }
             GameInstruction::GameAction {
@@ -351,68 +1614,205 @@ impl Handler<PhysicsInstruction> for PhysicsEngine {
                 d,
                 click,
                 dir,
-            } => {
-                // TODO: Actually handle this error
-                // If a game action was sent, the player_body should be registered
-                let PhysicsPlayerInfo {
-                    handle,
-                    dir: mut_dir,
-                    bullet_cooldown,
-                    ..
-                } = self.player_body_handles.get_mut(&msg.sent_from).unwrap();
-                let rigid_body = self.rigid_body_set.get_mut(*handle).unwrap();
-                let force: f32 = self.state.settings.impulse_force;
-                *mut_dir = dir;
-
-                if w {
-                    PhysicsEngine::apply_force_from_dir(rigid_body, vector![0.0, -force])
-                }
-                if a {
-                    PhysicsEngine::apply_force_from_dir(rigid_body, vector![-force, 0.0])
-                }
-                if s {
-                    PhysicsEngine::apply_force_from_dir(rigid_body, vector![0.0, force])
-                }
-                if d {
-                    PhysicsEngine::apply_force_from_dir(rigid_body, vector![force, 0.0])
-                }
+                seq,
+            } => self.set_input(
+                &msg.sent_from,
+                PlayerInput {
+                    w,
+                    a,
+                    s,
+                    d,
+                    dir,
+                    click,
+                    seq,
+                },
+            ),
+        }
+    }
+}
 
-                if click && *bullet_cooldown <= 0 {
-                    let dir = dir + PI / 2.0;
-                    let bullet_speed = self.state.settings.bullet_speed;
-                    let unit_velocity = vector![dir.cos(), dir.sin()];
-                    let trans = rigid_body.translation().clone();
-                    PhysicsEngine::apply_force_from_dir(
-                        rigid_body,
-                        unit_velocity * self.state.settings.impulse_force * -5.0,
-                    );
-                    let rigid_body = RigidBodyBuilder::new(RigidBodyType::Dynamic)
-                        .translation(vector![trans.x, trans.y] + unit_velocity * 30.0)
-                        .linear_damping(0.25)
-                        .ccd_enabled(true)
-                        .linvel(unit_velocity * bullet_speed)
-                        .build();
-                    let handle = self.rigid_body_set.insert(rigid_body);
-                    let collider = ColliderBuilder::ball(self.state.settings.bullet_size)
-                        .density(1.0)
-                        .restitution(0.93)
-                        .build();
-                    self.collider_set.insert_with_parent(
-                        collider,
-                        handle,
-                        &mut self.rigid_body_set,
-                    );
-                    self.bullet_handles.insert(handle, 0);
+impl Handler<NextArena> for PhysicsEngine {
+    type Result = ();
 
-                    *bullet_cooldown = 25;
-                }
-            }
-        }
+    fn handle(&mut self, _msg: NextArena, _ctx: &mut Self::Context) -> Self::Result {
+        self.next_arena();
     }
 }
 
-fn health_convert(num: u128) -> f32 {
-    let n = num.max(5000);
-    let n = ((n - 5000) as f32) / 5000.0;
-    n
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_mode::GameModeKind;
+    use crate::state::Settings;
+    use actix::dev::channel::channel;
+
+    fn engine(game_mode: GameModeKind) -> PhysicsEngine {
+        let mut settings = Settings::load();
+        settings.respawn_delay_ms = 0;
+        settings.game_mode = game_mode;
+        settings.teams = 2;
+        PhysicsEngine::new(Arc::new(InnerState::with_settings(settings)))
+    }
+
+    // An address whose receiver is gone, so anything sent to it goes nowhere
+    fn address() -> Addr<Ws> {
+        Addr::new(channel::<Ws>(16).0)
+    }
+
+    fn killed(engine: &PhysicsEngine) -> Vec<(EntityId, Option<EntityId>, Vec<EntityId>)> {
+        engine
+            .events
+            .iter()
+            .filter_map(|(_, event)| match event {
+                GameEvent::Kill {
+                    victim,
+                    killer,
+                    assists,
+                } => Some((*victim, *killer, assists.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn attackers_are_credited_across_respawns() {
+        let mut engine = engine(GameModeKind::FreeForAll);
+        let players: Vec<_> = (0..3).map(|_| address()).collect();
+        for player in &players {
+            engine.join_game(player.clone(), String::from("player"));
+        }
+        let victim = &players[0];
+        engine
+            .player_body_handles
+            .get_mut(victim)
+            .unwrap()
+            .attackers = vec![
+            (players[1].clone(), Instant::now()),
+            (players[2].clone(), Instant::now()),
+        ];
+
+        // Both attackers die, and the most recent one respawns, before the victim dies
+        engine.kill_player(&players[1]);
+        engine.kill_player(&players[2]);
+        engine.dead_players.get_mut(&players[2]).unwrap().requested = true;
+        engine.respawn_players();
+        let respawned = engine.player_body_handles[&players[2]].id;
+        let dead = engine.dead_players[&players[1]].id;
+        let id = engine.player_body_handles[victim].id;
+        engine.kill_player(victim);
+
+        assert_eq!(
+            killed(&engine).last(),
+            Some(&(id, Some(respawned), vec![dead]))
+        );
+        assert_eq!(engine.player_body_handles[&players[2]].stats.kills, 1);
+        assert_eq!(engine.dead_players[&players[1]].stats.assists, 1);
+
+        // Nobody is credited for a connection that left
+        engine.dead_players.remove(&players[1]);
+        let victim = &players[2];
+        engine
+            .player_body_handles
+            .get_mut(victim)
+            .unwrap()
+            .attackers = vec![(players[1].clone(), Instant::now())];
+        engine.kill_player(victim);
+        assert_eq!(killed(&engine).last(), Some(&(respawned, None, vec![])));
+    }
+
+    #[test]
+    fn spectating_after_respawning_is_ignored() {
+        let mut engine = engine(GameModeKind::FreeForAll);
+        let player = address();
+        engine.join_game(player.clone(), String::from("player"));
+        engine.kill_player(&player);
+        engine.dead_players.get_mut(&player).unwrap().requested = true;
+        engine.respawn_players();
+
+        // Sent while the connection was still dead
+        let spectate = PhysicsInstruction {
+            game_instruction: GameInstruction::Spectate,
+            sent_from: player.clone(),
+        };
+        engine.handle(spectate, &mut Context::new());
+        assert!(engine.spectators.is_empty());
+        assert!(engine.player_body_handles.contains_key(&player));
+    }
+
+    #[test]
+    fn spectating_while_dead_keeps_stats_and_team() {
+        let mut engine = engine(GameModeKind::TeamDeathmatch);
+        let players: Vec<_> = (0..4).map(|_| address()).collect();
+        for player in &players[..3] {
+            engine.join_game(player.clone(), String::from("player"));
+        }
+        let benched = &players[0];
+        engine
+            .player_body_handles
+            .get_mut(benched)
+            .unwrap()
+            .stats
+            .kills = 2;
+        engine.kill_player(benched);
+        engine.handle(
+            PhysicsInstruction {
+                game_instruction: GameInstruction::Spectate,
+                sent_from: benched.clone(),
+            },
+            &mut Context::new(),
+        );
+        assert!(engine.spectators.contains(benched));
+
+        // The team the benched player left open isn't handed out meanwhile
+        engine.join_game(players[3].clone(), String::from("player"));
+        let team = |engine: &PhysicsEngine, player| {
+            let id = engine.player_body_handles[player].id;
+            engine.components.get(id).unwrap().team
+        };
+        assert_eq!(team(&engine, &players[3]), Some(2));
+
+        engine.join_game(benched.clone(), String::from("player"));
+        assert!(!engine.spectators.contains(benched));
+        engine.respawn_players();
+        assert_eq!(engine.player_body_handles[benched].stats.kills, 2);
+        assert_eq!(team(&engine, benched), Some(1));
+    }
+
+    #[test]
+    fn entities_stay_visible_within_the_margin() {
+        let mut engine = engine(GameModeKind::FreeForAll);
+        let margin = engine.arena.settings.interest_margin;
+        let radius = engine.arena.settings.ball_size;
+        let (viewer, other) = (address(), address());
+        engine.join_game(viewer.clone(), String::from("viewer"));
+        engine.join_game(other.clone(), String::from("other"));
+        engine
+            .player_body_handles
+            .get_mut(&viewer)
+            .unwrap()
+            .viewport = Coords { x: 400.0, y: 400.0 };
+        let id = engine.player_body_handles[&other].id;
+        let center = vector![500.0, 500.0];
+        let mut sees = |x: Real| {
+            for (player, position) in [(&viewer, center), (&other, center + vector![x, 0.0])] {
+                let handle = engine.player_body_handles[player].handle;
+                engine.rigid_body_set[handle].set_translation(position, true);
+            }
+            engine.update_interest();
+            engine.player_body_handles[&viewer].visible.contains(&id)
+        };
+
+        // Edges count, the other player is just out of view here
+        let outside = 200.0 + radius;
+        assert!(!sees(outside + 1.0));
+        assert!(sees(outside - 1.0));
+        assert!(sees(outside + margin - 1.0));
+        assert!(!sees(outside + margin + 1.0));
+        // Only coming into view again makes it visible
+        assert!(!sees(outside + 1.0));
+        assert!(sees(0.0));
+        // As far apart as maps allow
+        assert!(!sees(2_000_000.0));
+        assert!(sees(-1.0));
+    }
 }
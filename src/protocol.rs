@@ -0,0 +1,823 @@
+use crate::components::TeamId;
+use crate::map::Map;
+use crate::physics_engine::{Coords, EntityId, EntityMeta, GameEvent, GameOver, WorldSnapshot};
+use crate::state::{Phase, Settings};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+// Bump PROTOCOL_VERSION whenever the wire format changes
+// Clients older than MIN_PROTOCOL_VERSION are refused during the handshake, so only raise it
+// once the server no longer writes the older format, or every open tab gets refused
+pub const PROTOCOL_VERSION: u32 = 16;
+pub const MIN_PROTOCOL_VERSION: u32 = 15;
+
+// First version with the flag a player carries in snapshots
+const FLAG_VERSION: u32 = 16;
+
+// Ways a snapshot can be put on the wire
+// Clients list the ones they support in Hello, in order of preference
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    BinaryDelta,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "Json" => Some(Encoding::Json),
+            "BinaryDelta" => Some(Encoding::BinaryDelta),
+            _ => None,
+        }
+    }
+}
+
+// Why a client message was rejected
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    // Not valid JSON, or not a known ClientInstruction
+    MalformedMessage,
+    // Binary frames are only sent by the server
+    UnexpectedBinary,
+    DuplicateHello,
+    // Requested phase can't be reached from the current one, see Phase::transition
+    InvalidTransition,
+    // Game instruction sent while not Joined
+    NotJoined,
+    // Wrong admin token, or an admin instruction sent without authenticating
+    Unauthorized,
+    // A map edit that would leave the map invalid, or couldn't be saved
+    // Not counted as a strike
+    EditRejected,
+}
+
+// Text messages from server to client
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub enum ServerMessage<'a> {
+    // Handshake accepted, everything the client needs to start rendering
+    Welcome {
+        version: u32,
+        encoding: Encoding,
+        tick_rate: u32,
+        settings: &'a Settings,
+        // Generated maps can't be fetched from /maps, so the geometry is sent along
+        map: &'a Map,
+    },
+    // Handshake refused, the connection is closed right after
+    Refused {
+        reason: String,
+    },
+    // The connection moved to a new phase
+    Phase {
+        phase: Phase,
+    },
+    // A client message was rejected and counted as a strike
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    // New entities, always sent before the first snapshot that contains them
+    Spawned {
+        entities: &'a [EntityMeta],
+    },
+    // Entities that no longer exist
+    Despawned {
+        ids: &'a [EntityId],
+    },
+    // Full snapshot, only sent with the Json encoding
+    Snapshot(&'a Snapshot),
+    // Discrete events of the last tick, sent right after its snapshot
+    Events {
+        events: &'a [GameEvent],
+    },
+    // The player died, with how their match went
+    GameOver(&'a GameOver),
+    // The server rotated to another arena, everyone is respawned in it
+    // Entities of the old arena are despawned along with the next snapshot
+    MapChange {
+        map: &'a Map,
+        settings: &'a Settings,
+    },
+    // Admin rights granted
+    Authenticated,
+    // An admin edited the map being played
+    MapEdited {
+        map: &'a Map,
+    },
+    // Answer to an admin's Save edit
+    MapSaved {
+        name: &'a str,
+    },
+}
+
+/// Picks the protocol version to speak with a client
+/// The client's version is used as long as the server still supports it
+pub fn negotiate_version(client_version: u32) -> Result<u32, String> {
+    if client_version < MIN_PROTOCOL_VERSION {
+        Err(format!(
+            "protocol version {} is no longer supported, please reload the page (server supports {} to {})",
+            client_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ))
+    } else {
+        Ok(client_version.min(PROTOCOL_VERSION))
+    }
+}
+
+/// Picks the first encoding in the client's list that the server understands
+pub fn negotiate_encoding(client_encodings: &[String]) -> Result<Encoding, String> {
+    client_encodings
+        .iter()
+        .find_map(|name| Encoding::from_name(name))
+        .ok_or_else(|| format!("none of the encodings {:?} are supported", client_encodings))
+}
+
+// Binary snapshot wire format (all values little-endian):
+//
+//   u8   kind            KEYFRAME or DELTA
+//   u32  seq             sequence number of this snapshot
+//   u32  baseline_seq    (DELTA only) snapshot this delta applies to
+//   u32  timestamp       milliseconds since the connection started
+//   u32  my_id           our own entity, 0 when spectating
+//   u16  rtt_ms          round trip time the server measured for this connection
+//   f32  my_x, my_y, health
+//   u32  last_input_seq  last GameAction seq applied to this player
+//   f32  my_vx, my_vy
+//   u8   protected       1 while we have spawn protection, 0 otherwise
+//   u8   my_team         0 without a team, teams are numbered from 1
+//   u8   my_flag         team whose flag we carry, 0 for none
+//   u16  count, then per enemy:  u32 id, u8 field mask, masked fields
+//   u16  count, then per removed enemy:  u32 id
+//   u16  count, then per bullet:  u32 id, f32 x, f32 y
+//   u16  count, then per removed bullet:  u32 id
+//
+// Enemy fields follow the mask bit order: coords (f32 x, f32 y), dir (f32), health (f32),
+// protected (u8, 1 or 0), team (u8, 0 without a team), flag (u8, 0 for none).
+// A keyframe carries every entity with a full mask and no removals.
+// Static entity info such as usernames is sent separately in ServerMessage::Spawned.
+pub const KEYFRAME: u8 = 0;
+pub const DELTA: u8 = 1;
+
+pub const ENEMY_COORDS: u8 = 1 << 0;
+pub const ENEMY_DIR: u8 = 1 << 1;
+pub const ENEMY_HEALTH: u8 = 1 << 2;
+pub const ENEMY_PROTECTED: u8 = 1 << 3;
+pub const ENEMY_TEAM: u8 = 1 << 4;
+pub const ENEMY_FLAG: u8 = 1 << 5;
+const ENEMY_ALL: u8 =
+    ENEMY_COORDS | ENEMY_DIR | ENEMY_HEALTH | ENEMY_PROTECTED | ENEMY_TEAM | ENEMY_FLAG;
+
+// How many unacknowledged snapshots are kept around as possible baselines
+// At 128 ticks a second this covers two seconds of round trip
+const SNAPSHOT_HISTORY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EnemySnapshot {
+    pub id: EntityId,
+    pub coords: Coords,
+    pub dir: f32,
+    pub health: f32,
+    pub protected: bool,
+    pub team: Option<TeamId>,
+    // Team whose flag the enemy carries
+    pub flag: Option<TeamId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BulletSnapshot {
+    pub id: EntityId,
+    pub coords: Coords,
+}
+
+// Everything a client sees in a single tick
+// enemies and bullets are kept sorted by id so that two snapshots can be diffed in one pass
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub seq: u32,
+    pub timestamp: u32,
+    pub rtt_ms: u16,
+    pub my_id: EntityId,
+    pub my_coords: Coords,
+    pub my_velocity: Coords,
+    pub last_input_seq: u32,
+    pub health: f32,
+    pub protected: bool,
+    pub team: Option<TeamId>,
+    pub flag: Option<TeamId>,
+    pub enemies: Vec<EnemySnapshot>,
+    pub bullets: Vec<BulletSnapshot>,
+}
+
+impl Snapshot {
+    /// Derives what a single client sees from the world snapshot
+    /// me is the client's own player, or None for spectators who are placed at camera
+    /// visible is the sorted list of entities the client can see, None to see everything
+    /// seq, timestamp and rtt_ms are left at 0 for the caller to fill in
+    pub fn view(
+        world: &WorldSnapshot,
+        me: Option<EntityId>,
+        visible: Option<&[EntityId]>,
+        camera: Coords,
+    ) -> Snapshot {
+        let can_see = |id: EntityId| visible.is_none_or(|v| v.binary_search(&id).is_ok());
+        let me = me.and_then(|id| {
+            world
+                .players
+                .binary_search_by_key(&id, |p| p.id)
+                .ok()
+                .map(|i| &world.players[i])
+        });
+
+        let enemies = world
+            .players
+            .iter()
+            .filter(|p| Some(p.id) != me.map(|me| me.id) && can_see(p.id))
+            .map(|p| EnemySnapshot {
+                id: p.id,
+                coords: p.coords,
+                dir: p.dir,
+                health: p.health,
+                protected: p.protected,
+                team: p.team,
+                flag: p.flag,
+            })
+            .collect();
+        let bullets = world
+            .bullets
+            .iter()
+            .filter(|b| can_see(b.id))
+            .map(|b| BulletSnapshot {
+                id: b.id,
+                coords: b.coords,
+            })
+            .collect();
+
+        Snapshot {
+            seq: 0,
+            timestamp: 0,
+            rtt_ms: 0,
+            my_id: me.map_or(0, |me| me.id),
+            my_coords: me.map_or(camera, |me| me.coords),
+            my_velocity: me.map_or(Coords { x: 0.0, y: 0.0 }, |me| me.velocity),
+            last_input_seq: me.map_or(0, |me| me.last_input_seq),
+            health: me.map_or(0.0, |me| me.health),
+            protected: me.is_some_and(|me| me.protected),
+            team: me.and_then(|me| me.team),
+            flag: me.and_then(|me| me.flag),
+            enemies,
+            bullets,
+        }
+    }
+}
+
+/// Per-connection encoder
+/// Remembers recently sent snapshots so that each new one can be sent
+/// as a delta against the last snapshot the client acknowledged
+pub struct SnapshotEncoder {
+    // Protocol version agreed on with the client, snapshots are written in its format
+    version: u32,
+    history: VecDeque<Snapshot>,
+    next_seq: u32,
+    acked_seq: Option<u32>,
+}
+
+impl Default for SnapshotEncoder {
+    fn default() -> Self {
+        SnapshotEncoder::new()
+    }
+}
+
+impl SnapshotEncoder {
+    pub fn new() -> Self {
+        SnapshotEncoder::with_version(PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(version: u32) -> Self {
+        SnapshotEncoder {
+            version,
+            history: VecDeque::with_capacity(SNAPSHOT_HISTORY),
+            next_seq: 0,
+            acked_seq: None,
+        }
+    }
+
+    pub fn next_seq(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        seq
+    }
+
+    // Client has received and stored snapshot seq
+    // Acks older than the current baseline are ignored
+    pub fn ack(&mut self, seq: u32) {
+        match self.acked_seq {
+            Some(acked) if seq.wrapping_sub(acked) as i32 <= 0 => (),
+            _ => self.acked_seq = Some(seq),
+        }
+    }
+
+    // Forget the baseline, forcing the next snapshot to be a keyframe
+    pub fn request_keyframe(&mut self) {
+        self.acked_seq = None;
+        self.history.clear();
+    }
+
+    pub fn encode(&mut self, snapshot: Snapshot) -> Vec<u8> {
+        let baseline = self
+            .acked_seq
+            .and_then(|seq| self.history.iter().find(|s| s.seq == seq));
+        let bytes = match baseline {
+            Some(baseline) => encode_delta(self.version, baseline, &snapshot),
+            None => encode_keyframe(self.version, &snapshot),
+        };
+
+        // Snapshots older than the ack can never become a baseline again
+        if let Some(acked) = self.acked_seq {
+            while let Some(front) = self.history.front() {
+                if front.seq != acked && (front.seq.wrapping_sub(acked) as i32) < 0 {
+                    self.history.pop_front();
+                } else {
+                    break;
+                }
+            }
+        }
+        if self.history.len() == SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(snapshot);
+        bytes
+    }
+}
+
+pub fn encode_keyframe(version: u32, snapshot: &Snapshot) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.u8(KEYFRAME);
+    w.u32(snapshot.seq);
+    write_header(&mut w, version, snapshot);
+
+    w.u16(snapshot.enemies.len() as u16);
+    for enemy in snapshot.enemies.iter() {
+        write_enemy(&mut w, enemy, enemy_fields(version));
+    }
+    w.u16(0);
+
+    w.u16(snapshot.bullets.len() as u16);
+    for bullet in snapshot.bullets.iter() {
+        write_bullet(&mut w, bullet);
+    }
+    w.u16(0);
+    w.finish()
+}
+
+pub fn encode_delta(version: u32, baseline: &Snapshot, snapshot: &Snapshot) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.u8(DELTA);
+    w.u32(snapshot.seq);
+    w.u32(baseline.seq);
+    write_header(&mut w, version, snapshot);
+
+    let (changed, removed) = diff(
+        &baseline.enemies,
+        &snapshot.enemies,
+        |e| e.id,
+        |old, new| enemy_mask(old, new) & enemy_fields(version),
+    );
+    w.u16(changed.len() as u16);
+    for (enemy, mask) in changed {
+        write_enemy(&mut w, enemy, mask);
+    }
+    w.u16(removed.len() as u16);
+    for id in removed {
+        w.u32(id);
+    }
+
+    let (changed, removed) = diff(
+        &baseline.bullets,
+        &snapshot.bullets,
+        |b| b.id,
+        |old, new| {
+            if old.is_none_or(|old| old.coords != new.coords) {
+                ENEMY_COORDS
+            } else {
+                0
+            }
+        },
+    );
+    w.u16(changed.len() as u16);
+    for (bullet, _) in changed {
+        write_bullet(&mut w, bullet);
+    }
+    w.u16(removed.len() as u16);
+    for id in removed {
+        w.u32(id);
+    }
+    w.finish()
+}
+
+// Enemy fields the given protocol version knows about
+fn enemy_fields(version: u32) -> u8 {
+    if version >= FLAG_VERSION {
+        ENEMY_ALL
+    } else {
+        ENEMY_ALL & !ENEMY_FLAG
+    }
+}
+
+fn write_header(w: &mut Writer, version: u32, snapshot: &Snapshot) {
+    w.u32(snapshot.timestamp);
+    w.u32(snapshot.my_id);
+    w.u16(snapshot.rtt_ms);
+    w.f32(snapshot.my_coords.x);
+    w.f32(snapshot.my_coords.y);
+    w.f32(snapshot.health);
+    w.u32(snapshot.last_input_seq);
+    w.f32(snapshot.my_velocity.x);
+    w.f32(snapshot.my_velocity.y);
+    w.u8(snapshot.protected as u8);
+    w.u8(snapshot.team.unwrap_or(0) as u8);
+    if version >= FLAG_VERSION {
+        w.u8(snapshot.flag.unwrap_or(0) as u8);
+    }
+}
+
+fn write_enemy(w: &mut Writer, enemy: &EnemySnapshot, mask: u8) {
+    w.u32(enemy.id);
+    w.u8(mask);
+    if mask & ENEMY_COORDS != 0 {
+        w.f32(enemy.coords.x);
+        w.f32(enemy.coords.y);
+    }
+    if mask & ENEMY_DIR != 0 {
+        w.f32(enemy.dir);
+    }
+    if mask & ENEMY_HEALTH != 0 {
+        w.f32(enemy.health);
+    }
+    if mask & ENEMY_PROTECTED != 0 {
+        w.u8(enemy.protected as u8);
+    }
+    if mask & ENEMY_TEAM != 0 {
+        w.u8(enemy.team.unwrap_or(0) as u8);
+    }
+    if mask & ENEMY_FLAG != 0 {
+        w.u8(enemy.flag.unwrap_or(0) as u8);
+    }
+}
+
+fn write_bullet(w: &mut Writer, bullet: &BulletSnapshot) {
+    w.u32(bullet.id);
+    w.f32(bullet.coords.x);
+    w.f32(bullet.coords.y);
+}
+
+// Which fields of an enemy differ from the baseline
+fn enemy_mask(old: Option<&EnemySnapshot>, new: &EnemySnapshot) -> u8 {
+    let old = match old {
+        Some(old) => old,
+        None => return ENEMY_ALL,
+    };
+    let mut mask = 0;
+    if old.coords != new.coords {
+        mask |= ENEMY_COORDS;
+    }
+    if old.dir != new.dir {
+        mask |= ENEMY_DIR;
+    }
+    if old.health != new.health {
+        mask |= ENEMY_HEALTH;
+    }
+    if old.protected != new.protected {
+        mask |= ENEMY_PROTECTED;
+    }
+    if old.team != new.team {
+        mask |= ENEMY_TEAM;
+    }
+    if old.flag != new.flag {
+        mask |= ENEMY_FLAG;
+    }
+    mask
+}
+
+// Merges two id-sorted lists
+// Returns the entities that are new or changed (with their change mask), and the ids that disappeared
+fn diff<'a, T>(
+    old: &[T],
+    new: &'a [T],
+    id: impl Fn(&T) -> EntityId,
+    mask: impl Fn(Option<&T>, &T) -> u8,
+) -> (Vec<(&'a T, u8)>, Vec<EntityId>) {
+    let mut changed = vec![];
+    let mut removed = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        match (old.get(i), new.get(j)) {
+            (Some(o), Some(n)) if id(o) == id(n) => {
+                let m = mask(Some(o), n);
+                if m != 0 {
+                    changed.push((n, m));
+                }
+                i += 1;
+                j += 1;
+            }
+            (Some(o), Some(n)) if id(o) < id(n) => {
+                removed.push(id(o));
+                i += 1;
+            }
+            (Some(o), None) => {
+                removed.push(id(o));
+                i += 1;
+            }
+            (_, Some(n)) => {
+                changed.push((n, mask(None, n)));
+                j += 1;
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    (changed, removed)
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer {
+            buf: Vec::with_capacity(256),
+        }
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What a client learns from an encoded snapshot, enough to check the encoder's choices
+    #[derive(Debug, PartialEq)]
+    struct Decoded {
+        kind: u8,
+        seq: u32,
+        baseline: Option<u32>,
+        // Id and field mask of every enemy sent
+        enemies: Vec<(EntityId, u8)>,
+        removed_enemies: Vec<EntityId>,
+        bullets: Vec<EntityId>,
+        removed_bullets: Vec<EntityId>,
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn take(&mut self, n: usize) -> &[u8] {
+            let (taken, rest) = self.0.split_at(n);
+            self.0 = rest;
+            taken
+        }
+
+        fn u8(&mut self) -> u8 {
+            self.take(1)[0]
+        }
+
+        fn u16(&mut self) -> u16 {
+            u16::from_le_bytes(self.take(2).try_into().unwrap())
+        }
+
+        fn u32(&mut self) -> u32 {
+            u32::from_le_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn ids(&mut self) -> Vec<EntityId> {
+            (0..self.u16()).map(|_| self.u32()).collect()
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Decoded {
+        decode_version(bytes, PROTOCOL_VERSION)
+    }
+
+    fn decode_version(bytes: &[u8], version: u32) -> Decoded {
+        let mut r = Reader(bytes);
+        let kind = r.u8();
+        let seq = r.u32();
+        let baseline = (kind == DELTA).then(|| r.u32());
+        // timestamp, my_id, rtt_ms, my_x, my_y, health, last_input_seq, my_vx, my_vy,
+        // protected, my_team, then my_flag since FLAG_VERSION
+        r.take(4 + 4 + 2 + 4 * 6 + 1 + 1);
+        if version >= FLAG_VERSION {
+            r.take(1);
+        }
+        let enemies = (0..r.u16())
+            .map(|_| {
+                let (id, mask) = (r.u32(), r.u8());
+                let fields = [
+                    (ENEMY_COORDS, 8),
+                    (ENEMY_DIR, 4),
+                    (ENEMY_HEALTH, 4),
+                    (ENEMY_PROTECTED, 1),
+                    (ENEMY_TEAM, 1),
+                    (ENEMY_FLAG, 1),
+                ];
+                for (bit, size) in fields {
+                    if mask & bit != 0 {
+                        r.take(size);
+                    }
+                }
+                (id, mask)
+            })
+            .collect();
+        let removed_enemies = r.ids();
+        let bullets = (0..r.u16())
+            .map(|_| {
+                let id = r.u32();
+                r.take(8);
+                id
+            })
+            .collect();
+        let removed_bullets = r.ids();
+        assert!(r.0.is_empty(), "trailing bytes");
+        Decoded {
+            kind,
+            seq,
+            baseline,
+            enemies,
+            removed_enemies,
+            bullets,
+            removed_bullets,
+        }
+    }
+
+    fn enemy(id: EntityId, x: f32) -> EnemySnapshot {
+        EnemySnapshot {
+            id,
+            coords: Coords { x, y: 0.0 },
+            dir: 0.0,
+            health: 1.0,
+            protected: false,
+            team: None,
+            flag: None,
+        }
+    }
+
+    fn bullet(id: EntityId, x: f32) -> BulletSnapshot {
+        BulletSnapshot {
+            id,
+            coords: Coords { x, y: 0.0 },
+        }
+    }
+
+    fn snapshot(
+        encoder: &mut SnapshotEncoder,
+        enemies: Vec<EnemySnapshot>,
+        bullets: Vec<BulletSnapshot>,
+    ) -> Snapshot {
+        Snapshot {
+            seq: encoder.next_seq(),
+            timestamp: 0,
+            rtt_ms: 0,
+            my_id: 1,
+            my_coords: Coords { x: 0.0, y: 0.0 },
+            my_velocity: Coords { x: 0.0, y: 0.0 },
+            last_input_seq: 0,
+            health: 1.0,
+            protected: false,
+            team: None,
+            flag: None,
+            enemies,
+            bullets,
+        }
+    }
+
+    #[test]
+    fn deltas_against_the_acked_snapshot() {
+        let mut encoder = SnapshotEncoder::new();
+        let first = snapshot(
+            &mut encoder,
+            vec![enemy(2, 0.0), enemy(3, 0.0)],
+            vec![bullet(10, 0.0), bullet(11, 0.0)],
+        );
+        let keyframe = decode(&encoder.encode(first));
+        assert_eq!(keyframe.kind, KEYFRAME);
+        assert_eq!(keyframe.enemies, vec![(2, ENEMY_ALL), (3, ENEMY_ALL)]);
+        assert_eq!(keyframe.bullets, vec![10, 11]);
+
+        // Nothing acked yet, so still a keyframe
+        let second = snapshot(&mut encoder, vec![enemy(2, 0.0)], vec![]);
+        assert_eq!(decode(&encoder.encode(second)).kind, KEYFRAME);
+
+        // Against 0: enemy 2 unchanged, 3 gone, 4 new; bullet 10 moved, 11 gone
+        encoder.ack(0);
+        let third = snapshot(
+            &mut encoder,
+            vec![enemy(2, 0.0), enemy(4, 0.0)],
+            vec![bullet(10, 5.0)],
+        );
+        assert_eq!(
+            decode(&encoder.encode(third)),
+            Decoded {
+                kind: DELTA,
+                seq: 2,
+                baseline: Some(0),
+                enemies: vec![(4, ENEMY_ALL)],
+                removed_enemies: vec![3],
+                bullets: vec![10],
+                removed_bullets: vec![11],
+            }
+        );
+
+        // Only what changed since 2 is sent
+        encoder.ack(2);
+        let fourth = snapshot(
+            &mut encoder,
+            vec![enemy(2, 1.0), enemy(4, 0.0)],
+            vec![bullet(10, 5.0)],
+        );
+        let delta = decode(&encoder.encode(fourth));
+        assert_eq!(delta.baseline, Some(2));
+        assert_eq!(delta.enemies, vec![(2, ENEMY_COORDS)]);
+        assert!(delta.removed_enemies.is_empty());
+        assert!(delta.bullets.is_empty() && delta.removed_bullets.is_empty());
+    }
+
+    #[test]
+    fn stale_acks_are_ignored() {
+        let mut encoder = SnapshotEncoder::new();
+        for _ in 0..3 {
+            let s = snapshot(&mut encoder, vec![], vec![]);
+            encoder.encode(s);
+        }
+        encoder.ack(2);
+        encoder.ack(1);
+        let s = snapshot(&mut encoder, vec![], vec![]);
+        assert_eq!(decode(&encoder.encode(s)).baseline, Some(2));
+    }
+
+    #[test]
+    fn keyframe_once_the_baseline_is_forgotten() {
+        let mut encoder = SnapshotEncoder::new();
+        let s = snapshot(&mut encoder, vec![enemy(2, 0.0)], vec![]);
+        encoder.encode(s);
+        encoder.ack(0);
+        for _ in 0..SNAPSHOT_HISTORY {
+            let s = snapshot(&mut encoder, vec![enemy(2, 0.0)], vec![]);
+            assert_eq!(decode(&encoder.encode(s)).baseline, Some(0));
+        }
+        // Snapshot 0 has been pushed out of the history by now
+        let s = snapshot(&mut encoder, vec![enemy(2, 0.0)], vec![]);
+        let decoded = decode(&encoder.encode(s));
+        assert_eq!(decoded.kind, KEYFRAME);
+        assert_eq!(decoded.enemies, vec![(2, ENEMY_ALL)]);
+
+        // So is a keyframe that was asked for
+        encoder.ack(SNAPSHOT_HISTORY as u32);
+        encoder.request_keyframe();
+        let s = snapshot(&mut encoder, vec![], vec![]);
+        assert_eq!(decode(&encoder.encode(s)).kind, KEYFRAME);
+    }
+
+    #[test]
+    fn older_clients_get_their_own_format() {
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION),
+            Ok(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert!(negotiate_version(MIN_PROTOCOL_VERSION - 1).is_err());
+
+        let mut encoder = SnapshotEncoder::with_version(FLAG_VERSION - 1);
+        let s = snapshot(&mut encoder, vec![enemy(2, 0.0)], vec![]);
+        let keyframe = decode_version(&encoder.encode(s), FLAG_VERSION - 1);
+        assert_eq!(keyframe.enemies, vec![(2, ENEMY_ALL & !ENEMY_FLAG)]);
+
+        // A flag being picked up is news only to newer clients
+        encoder.ack(0);
+        let mut carrier = enemy(2, 0.0);
+        carrier.flag = Some(1);
+        let s = snapshot(&mut encoder, vec![carrier], vec![]);
+        let delta = decode_version(&encoder.encode(s), FLAG_VERSION - 1);
+        assert!(delta.enemies.is_empty());
+    }
+}
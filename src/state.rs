@@ -1,55 +1,242 @@
 use std::fs::File;
 use std::io::BufReader;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use crate::custom_ws::Ws;
+use crate::game_mode::GameModeKind;
+use crate::generator::Generator;
+use crate::map::Map;
 use crate::physics_engine::PhysicsEngine;
 use actix::Addr;
 use dashmap::DashMap;
 
-use serde::Deserialize;
-use serde_json;
+use serde::{Deserialize, Serialize};
 
-pub struct PlayerInfo {
-    pub username: String,
+// Where a connection is in its lifetime
+// Enforced by the websocket actor, see Phase::transition for the allowed moves
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Phase {
+    // Socket open, no body in the arena yet
+    #[default]
+    Connected,
+    // Has a body and is playing
+    Joined,
+    // Body was destroyed
+    Dead,
+    // Watching without a body
+    Spectating,
+    // Connection is closing, nothing else may happen
+    Leaving,
 }
 
-impl Default for PlayerInfo {
-    fn default() -> Self {
-        PlayerInfo {
-            username: String::new(),
+impl Phase {
+    // Returns the new phase, or why the move isn't allowed
+    pub fn transition(self, to: Phase) -> Result<Phase, String> {
+        use Phase::*;
+        match (self, to) {
+            (Leaving, _) => Err(String::from("connection is leaving")),
+            (_, Leaving)
+            | (Connected, Joined)
+            | (Connected, Spectating)
+            | (Joined, Dead)
+            | (Dead, Joined)
+            | (Dead, Spectating)
+            | (Spectating, Joined) => Ok(to),
+            (from, to) => Err(format!("cannot go from {:?} to {:?}", from, to)),
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Default)]
+pub struct PlayerInfo {
+    pub username: String,
+    pub phase: Phase,
+    // Measured by the websocket heartbeat, None until the first pong
+    pub rtt: Option<Duration>,
+}
+
+// Kinds of collider the collision rules are written in terms of
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum BodyClass {
+    Player,
+    Bullet,
+    Wall,
+}
+
+impl BodyClass {
+    pub const ALL: [BodyClass; 3] = [BodyClass::Player, BodyClass::Bullet, BodyClass::Wall];
+}
+
+// What happens when two kinds of collider touch, the order of the pair doesn't matter
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CollisionRule {
+    pub between: (BodyClass, BodyClass),
+    // Whether they bounce off each other, if not they pass through and deal no damage
+    pub collide: bool,
+    // Health taken from each player in the pair, before armor
+    pub damage: f32,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Settings {
-    pub arena_width: f32,
-    pub arena_height: f32,
+    // Arenas to cycle through, starting with the first
+    pub map_rotation: Vec<RotationEntry>,
+    // How long each arena is played for, 0 to only change arenas on admin command
+    pub map_rotation_interval_ms: u64,
+    // Rules of the game, see game_mode.rs
+    pub game_mode: GameModeKind,
     pub ball_size: f32,
     pub bullet_size: f32,
     pub bullet_speed: f32,
     pub impulse_force: f32,
     pub damping: f32,
-    pub bullet_damage: u128,
+    pub max_health: f32,
+    // Fraction of incoming damage absorbed by players, from 0 to 1
+    pub armor: f32,
+    // Pairs without a rule pass through each other
+    pub collision_rules: Vec<CollisionRule>,
+    // Invalid client messages tolerated before the connection is closed
+    pub max_strikes: u32,
+    // Largest viewport a client may ask for, also used until it sends one
+    pub view_width: f32,
+    pub view_height: f32,
+    // How far past the viewport an entity has to go before it stops being sent
+    pub interest_margin: f32,
+    // How often clients are pinged
+    pub heartbeat_interval_ms: u64,
+    // Connections that send nothing (not even a pong) for this long are closed
+    pub idle_timeout_ms: u64,
+    // Whether players can be hurt by their own bullets
+    pub self_damage: bool,
+    // How recently a player must have hit someone to get an assist on their death
+    pub assist_window_ms: u64,
+    // Time between dying and being able to respawn
+    pub respawn_delay_ms: u64,
+    // Respawn as soon as the delay is over, without waiting for a Respawn message
+    pub auto_respawn: bool,
+    // Damage is ignored for this long after spawning, or until the player fires
+    pub spawn_protection_ms: u64,
+    // Number of teams in team game modes, at most 255
+    pub teams: u32,
+    // Whether bullets hurt teammates
+    pub friendly_fire: bool,
+    // Kills a team needs to win a TeamDeathmatch round, 0 for no limit
+    pub kill_limit: u32,
+    // Captures a team needs to win a CaptureTheFlag round, 0 for no limit
+    pub capture_limit: u32,
+}
+
+// One arena of the map rotation
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RotationEntry {
+    // Name of the map file in maps/, or of the generated map
+    pub map: String,
+    // Generates the map instead of reading it from maps/
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<Generator>,
+    // Settings that differ from settings.json while this arena is played
+    #[serde(default)]
+    pub settings: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Settings {
+    pub fn load() -> Settings {
+        let file = File::open(PathBuf::from("./static/settings.json")).unwrap();
+        serde_json::from_reader(BufReader::new(file)).unwrap()
+    }
+
+    // Copy of these settings with some fields replaced
+    pub fn with_overrides(
+        &self,
+        overrides: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Settings, String> {
+        let mut value = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        if let Some(fields) = value.as_object_mut() {
+            for (key, v) in overrides {
+                if !fields.contains_key(key) {
+                    return Err(format!("unknown setting {:?}", key));
+                }
+                fields.insert(key.clone(), v.clone());
+            }
+        }
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    pub fn collision_rule(&self, a: BodyClass, b: BodyClass) -> Option<&CollisionRule> {
+        self.collision_rules
+            .iter()
+            .find(|rule| rule.between == (a, b) || rule.between == (b, a))
+    }
+}
+
+// The map being played and the settings that go with it
+#[derive(Clone)]
+pub struct Arena {
+    pub map: Arc<Map>,
+    pub settings: Arc<Settings>,
+}
+
+impl Arena {
+    /// Loads an entry of the map rotation on top of the base settings
+    pub fn load(settings: &Settings, entry: &RotationEntry) -> Result<Arena, String> {
+        let settings = settings.with_overrides(&entry.settings)?;
+        let map = match &entry.generator {
+            Some(generator) => {
+                let seed = generator.seed.unwrap_or_else(rand::random);
+                let map = generator.generate(&entry.map, seed, settings.ball_size)?;
+                map.validate()?;
+                map
+            }
+            None => Map::load(&entry.map)?,
+        };
+        Ok(Arena {
+            map: Arc::new(map),
+            settings: Arc::new(settings),
+        })
+    }
 }
 
 pub struct InnerState {
     pub connected_players: DashMap<Addr<Ws>, PlayerInfo>, // Lockless!
+    // As loaded from settings.json, see arena for what is in effect
     pub settings: Settings,
+    // Changed by the physics engine as it goes through the map rotation
+    pub arena: RwLock<Arena>,
+    // Lets a connection edit the map, taken from the ADMIN_TOKEN environment variable
+    // Kept out of Settings since those are sent to every client
+    pub admin_token: Option<String>,
 }
 
 impl InnerState {
     pub fn new() -> Self {
-        let file = File::open(PathBuf::from("./static/settings.json")).unwrap();
-        let reader = BufReader::new(file);
-        let settings = serde_json::from_reader(reader).unwrap();
+        InnerState::with_settings(Settings::load())
+    }
+
+    pub fn with_settings(settings: Settings) -> Self {
+        let entry = settings
+            .map_rotation
+            .first()
+            .expect("map_rotation needs at least one entry");
+        let arena = Arena::load(&settings, entry).unwrap();
         InnerState {
             connected_players: DashMap::new(),
             settings,
+            arena: RwLock::new(arena),
+            admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
         }
     }
+
+    pub fn arena(&self) -> Arena {
+        self.arena.read().unwrap().clone()
+    }
+}
+
+impl Default for InnerState {
+    fn default() -> Self {
+        InnerState::new()
+    }
 }
 
 // State holds overall application state
@@ -63,7 +250,7 @@ pub struct State {
 impl State {
     pub fn new(physics_engine_address: Addr<PhysicsEngine>, inner: Arc<InnerState>) -> State {
         State {
-            inner: inner,
+            inner,
             physics_engine_address,
         }
     }
@@ -91,3 +278,40 @@ impl std::ops::Deref for State {
         &self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_transitions() {
+        use Phase::*;
+        let all = [Connected, Joined, Dead, Spectating, Leaving];
+        let allowed = [
+            (Connected, Joined),
+            (Connected, Spectating),
+            (Connected, Leaving),
+            (Joined, Dead),
+            (Joined, Leaving),
+            (Dead, Joined),
+            (Dead, Spectating),
+            (Dead, Leaving),
+            (Spectating, Joined),
+            (Spectating, Leaving),
+        ];
+        for from in all {
+            for to in all {
+                let result = from.transition(to);
+                if allowed.contains(&(from, to)) {
+                    assert_eq!(result, Ok(to), "{:?} to {:?}", from, to);
+                } else {
+                    assert!(result.is_err(), "{:?} to {:?} was allowed", from, to);
+                }
+            }
+        }
+        // Spelled out, since these are the ones that are easy to get wrong
+        assert!(Joined.transition(Spectating).is_err());
+        assert!(Leaving.transition(Leaving).is_err());
+        assert!(Leaving.transition(Connected).is_err());
+    }
+}
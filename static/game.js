@@ -1,4 +1,8 @@
-import {interpolate, render_sprite, translator, render_border, render_background, render_bullet} from './graphics.js';
+import {interpolate, render_sprite, translator, render_map, render_background, render_bullet, render_kill_feed, render_damage_number, render_shield, render_team_scores, render_flag} from './graphics.js';
+import {baselineOf, decodeSnapshot, PROTOCOL_VERSION} from './protocol.js';
+import {get_settings, set_settings} from './settings.js';
+
+const TEAM_COLORS = ['#e6194b', '#4363d8', '#3cb44b', '#f58231'];
 
 
 Array.prototype.pushSorted = function(el, compareFn) {
@@ -36,11 +40,28 @@ export class Game {
         };
         this.ctx = this.canvas.getContext("2d");
         this.connection = new WebSocket("ws://" + location.host + "/ws/")
+        this.connection.binaryType = "arraybuffer";
 
 
         // Array of game states
         this.game_state_buffer = [];
 
+        // Static info (username, color, kind) about every entity, by id
+        this.entities = new Map();
+
+        // Recent kills and joins as {text, time}, newest last
+        this.kill_feed = [];
+        // Damage dealt or taken recently as {target, damage, time}
+        this.damage_numbers = [];
+        // Score of every team as {team, score}, only in team game modes
+        this.team_scores = [];
+        // Where every team's flag is as {team, coords, carrier}, only in capture the flag
+        this.flags = [];
+
+        // Decoded snapshots by seq, kept as baselines for incoming deltas
+        this.snapshots = new Map();
+        this.last_snapshot_seq = null;
+
         
 
         this.mouse_cords = {
@@ -48,9 +69,13 @@ export class Game {
             y: 0,
         };
 
-        // When the connection is open, send some data to the server
+        // When the connection is open, introduce ourselves; we join once the server welcomes us
         this.connection.onopen = () => {
-            this.connection.send(JSON.stringify({type: "JoinGame", username: name}));
+            this.connection.send(JSON.stringify({
+                type: "Hello",
+                version: PROTOCOL_VERSION,
+                encodings: ["BinaryDelta", "Json"],
+            }));
         };
 
         // Log errors
@@ -63,20 +88,112 @@ export class Game {
 
         // Log messages from the server
         this.connection.onmessage = (e) => {
-            if (e.data != "game_over") {
-                let data = JSON.parse(e.data);
-                this.insertGameState(data);
-                if (this.start_timestamp === undefined) {
-                    this.start_timestamp = data.timestamp;
-                    this.js_epoch = performance.now();
+            if (e.data instanceof ArrayBuffer) {
+                let data = this.receiveSnapshot(e.data);
+                if (data !== null) {
+                    this.receiveGameState(data);
                 }
-            } else {
-                this.game_over = true;
-                $("#game-over").show();
                 return;
-            }    
+            }
+            let message = JSON.parse(e.data);
+            switch (message.type) {
+                case "Welcome":
+                    // A newer page talking to an older server can't read its snapshots
+                    if (message.version !== PROTOCOL_VERSION) {
+                        this.refused("server speaks protocol version " + message.version + ", this page needs " + PROTOCOL_VERSION);
+                        this.connection.close();
+                        break;
+                    }
+                    set_settings(message.settings);
+                    this.map = message.map;
+                    this.kill_feed.push({text: "Now playing " + this.mapLabel(message.map), time: performance.now()});
+                    const admin_token = new URLSearchParams(window.location.search).get("admin");
+                    if (admin_token !== null) {
+                        this.connection.send(JSON.stringify({type: "Authenticate", token: admin_token}));
+                    }
+                    this.connection.send(JSON.stringify({type: "JoinGame", username: name}));
+                    this.connection.send(JSON.stringify({
+                        type: "Viewport",
+                        width: this.canvas.width,
+                        height: this.canvas.height,
+                    }));
+                    break;
+                case "Refused":
+                    this.refused(message.reason);
+                    break;
+                case "Spawned":
+                    for (const entity of message.entities) {
+                        this.entities.set(entity.id, entity);
+                    }
+                    break;
+                case "Despawned":
+                    // Events about an entity can arrive right after it is gone, so forget it a bit later
+                    setTimeout(() => {
+                        for (const id of message.ids) {
+                            this.entities.delete(id);
+                        }
+                    }, 1000);
+                    break;
+                case "Phase":
+                    this.phase = message.phase;
+                    if (this.phase === "Joined") {
+                        this.game_over = false;
+                        clearInterval(this.respawn_timer);
+                        $("#game-over").hide();
+                    }
+                    break;
+                case "Error":
+                    console.log('Server rejected message (' + message.code + '): ' + message.message);
+                    break;
+                case "Events":
+                    for (const event of message.events) {
+                        this.receiveEvent(event);
+                    }
+                    break;
+                case "MapChange":
+                    set_settings(message.settings);
+                    this.map = message.map;
+                    this.damage_numbers = [];
+                    this.kill_feed.push({text: "Now playing " + this.mapLabel(message.map), time: performance.now()});
+                    break;
+                case "MapEdited":
+                    this.map = message.map;
+                    break;
+                case "Authenticated":
+                    // Map edits are made from the browser console, e.g.
+                    // edit_map({op: "PlaceSpawnPoint", at: {x: 100, y: 100}}) or edit_map({op: "Save", name: "mine"})
+                    window.edit_map = (edit) => this.connection.send(JSON.stringify({type: "EditMap", edit}));
+                    console.log("Admin rights granted, edit_map() is available");
+                    break;
+                case "MapSaved":
+                    console.log("Map saved as " + message.name);
+                    break;
+                case "GameOver":
+                    this.game_over = true;
+                    this.showGameOver(message);
+                    break;
+                case "Snapshot":
+                    this.receiveGameState({
+                        my_coords: message.my_coords,
+                        my_velocity: message.my_velocity,
+                        last_input_seq: message.last_input_seq,
+                        health: message.health,
+                        protected: message.protected,
+                        team: message.team,
+                        flag: message.flag,
+                        enemies: message.enemies,
+                        bullets: message.bullets.map((b) => ({id: b.id, ...b.coords})),
+                        timestamp: message.timestamp,
+                        my_id: message.my_id,
+                        rtt_ms: message.rtt_ms,
+                    });
+                    break;
+            }
         };
 
+        // Sequence number of the last GameAction sent, echoed back as last_input_seq
+        this.input_seq = 0;
+
         this.keydown = {
             w: false,
             a: false,
@@ -110,6 +227,12 @@ export class Game {
             this.keydown.click = false;
           }
 
+        // Asks to respawn once the countdown is over, or right away if it already is
+        $("#respawn-button").click((e) => {
+            e.stopPropagation();
+            this.connection.send(JSON.stringify({type: "Respawn"}));
+        });
+
         document.addEventListener("keydown", keyHandlerFactory(true), false);
         document.addEventListener("keyup", keyHandlerFactory(false), false);
         document.addEventListener("mousemove", moveHandler, false);
@@ -174,6 +297,166 @@ export class Game {
         return game_state;
     }
 
+    receiveGameState(data) {
+        this.insertGameState(data);
+        if (this.start_timestamp === undefined) {
+            this.start_timestamp = data.timestamp;
+            this.js_epoch = performance.now();
+        }
+    }
+
+    // Decode a binary snapshot against its baseline and acknowledge it
+    // Returns null if the baseline is missing, in which case a keyframe is requested
+    receiveSnapshot(buffer) {
+        const baseline_seq = baselineOf(buffer);
+        let baseline = undefined;
+        if (baseline_seq !== null) {
+            baseline = this.snapshots.get(baseline_seq);
+            if (baseline === undefined) {
+                this.connection.send(JSON.stringify({type: "RequestKeyframe"}));
+                return null;
+            }
+        }
+        const snapshot = decodeSnapshot(buffer, baseline);
+        this.snapshots.set(snapshot.seq, snapshot);
+        // The server never deltas against anything older than the baseline it just used
+        for (const seq of this.snapshots.keys()) {
+            if (baseline_seq !== null && seq < baseline_seq) {
+                this.snapshots.delete(seq);
+            } else {
+                break;
+            }
+        }
+        if (this.snapshots.size > 256) {
+            this.snapshots.delete(this.snapshots.keys().next().value);
+        }
+        this.last_snapshot_seq = snapshot.seq;
+        this.connection.send(JSON.stringify({type: "Ack", seq: snapshot.seq}));
+
+        return {
+            my_coords: snapshot.my_coords,
+            my_velocity: snapshot.my_velocity,
+            last_input_seq: snapshot.last_input_seq,
+            health: snapshot.health,
+            protected: snapshot.protected,
+            team: snapshot.team,
+            flag: snapshot.flag,
+            enemies: Array.from(snapshot.enemies.values()),
+            bullets: Array.from(snapshot.bullets.values()),
+            timestamp: snapshot.timestamp,
+            my_id: snapshot.my_id,
+            rtt_ms: snapshot.rtt_ms,
+        };
+    }
+
+    // Generated maps show their seed, so that the layout can be played again
+    mapLabel(map) {
+        return map.seed === undefined ? map.name : map.name + " (seed " + map.seed + ")";
+    }
+
+    // Players are colored by team in team game modes
+    teamColor(team) {
+        return team === null || team === undefined ? null : TEAM_COLORS[(team - 1) % TEAM_COLORS.length];
+    }
+
+    username(id) {
+        const meta = this.entities.get(id);
+        return meta && meta.username ? meta.username : "#" + id;
+    }
+
+    receiveEvent(event) {
+        const now = performance.now();
+        switch (event.type) {
+            case "PlayerJoined":
+                this.kill_feed.push({text: this.username(event.id) + " joined", time: now});
+                break;
+            case "PlayerLeft":
+                this.kill_feed.push({text: this.username(event.id) + " left", time: now});
+                break;
+            case "Kill": {
+                let text = this.username(event.victim) + " died";
+                if (event.killer !== null) {
+                    text = this.username(event.killer) + " killed " + this.username(event.victim);
+                }
+                if (event.assists.length > 0) {
+                    text += " (+" + event.assists.map((id) => this.username(id)).join(", ") + ")";
+                }
+                this.kill_feed.push({text, time: now});
+                break;
+            }
+            case "TeamScores":
+                this.team_scores = event.scores;
+                break;
+            case "RoundOver": {
+                const text = event.winner === null ? "Round over: draw" : "Round over: team " + event.winner + " wins";
+                this.kill_feed.push({text, time: now});
+                this.team_scores = [];
+                this.flags = [];
+                break;
+            }
+            case "Flags":
+                this.flags = event.flags;
+                break;
+            case "Flag": {
+                const flag = "team " + event.team + " flag";
+                const by = event.by === null ? null : this.username(event.by);
+                const text = {
+                    Taken: by + " took the " + flag,
+                    Dropped: "The " + flag + " was dropped",
+                    Returned: by === null ? "The " + flag + " was returned" : by + " returned the " + flag,
+                    Captured: by + " captured the " + flag,
+                }[event.action];
+                this.kill_feed.push({text, time: now});
+                break;
+            }
+            case "Hit":
+                this.damage_numbers.push({target: event.target, damage: event.damage, time: now});
+                break;
+        }
+        if (this.kill_feed.length > 5) {
+            this.kill_feed.shift();
+        }
+    }
+
+    // Nothing can be played on this connection
+    refused(reason) {
+        console.log('Server refused connection: ' + reason);
+        this.game_over = true;
+        $("#respawn-button").hide();
+        $("#game-over").show();
+    }
+
+    // Fill in the game over panel with the match statistics
+    showGameOver(message) {
+        const stats = message.stats;
+        const accuracy = stats.shots_fired > 0 ? Math.round(100 * stats.shots_hit / stats.shots_fired) : 0;
+        const lines = [
+            message.killer ? "Killed by " + message.killer.username : "You died",
+            ...(message.assists.length > 0 ? ["Assisted by " + message.assists.map((a) => a.username).join(", ")] : []),
+            "Placement: #" + message.placement,
+            "Time alive: " + (message.time_alive_ms / 1000).toFixed(1) + "s",
+            "Kills: " + stats.kills + ", assists: " + stats.assists,
+            "Damage dealt: " + Math.round(stats.damage_dealt) + ", taken: " + Math.round(stats.damage_taken),
+            "Accuracy: " + accuracy + "% (" + stats.shots_hit + "/" + stats.shots_fired + ")",
+        ];
+        $("#game-over-stats").empty().append(lines.map((line) => $("<div>").text(line)));
+        $("#game-over").show();
+
+        // Count down to when a respawn can happen, the server respawns automatically if it is set to
+        const respawn_at = performance.now() + message.respawn_in_ms;
+        const countdown = () => {
+            const left = Math.max(0, respawn_at - performance.now());
+            if (left > 0) {
+                $("#respawn-countdown").text("Respawn in " + Math.ceil(left / 1000) + "s");
+            } else {
+                $("#respawn-countdown").text(get_settings().auto_respawn ? "Respawning..." : "");
+            }
+        };
+        countdown();
+        clearInterval(this.respawn_timer);
+        this.respawn_timer = setInterval(countdown, 200);
+    }
+
     // Insert the game state into buffer and sort
     insertGameState(game_state) {
         this.game_state_buffer.pushSorted(game_state, function(a, b){return a.timestamp - b.timestamp});
@@ -193,21 +476,27 @@ export class Game {
             let t_game_state = {
                 my_coords : translate(original_game_state.my_coords.x, original_game_state.my_coords.y),
                 health: original_game_state.health,
+                protected: original_game_state.protected,
                 enemies: [],
                 bullets: []
             }
             for (let i = 0; i < original_game_state.enemies.length; i++) {
                 const enemy = original_game_state.enemies[i];
+                const meta = this.entities.get(enemy.id) || {username: "", color: 'blue'};
                 t_game_state.enemies.push({
                     coords: translate(enemy.coords.x, enemy.coords.y),
                     dir: enemy.dir,
-                    username: enemy.username,
+                    username: meta.username,
+                    color: this.teamColor(enemy.team) || meta.color,
                     health: enemy.health,
+                    protected: enemy.protected,
+                    flag: enemy.flag,
                 })
             }
             for (let i = 0; i < original_game_state.bullets.length; i++) {
                 const bullet = original_game_state.bullets[i];
-                t_game_state.bullets.push(translate(bullet.x, bullet.y))
+                const meta = this.entities.get(bullet.id) || {color: 'grey'};
+                t_game_state.bullets.push({...translate(bullet.x, bullet.y), color: meta.color})
             }
             
 
@@ -216,31 +505,75 @@ export class Game {
             
             // Sample events and send back info
             let k = this.keydown;
+            this.input_seq += 1;
             let s = JSON.stringify(
                 {
                     type: "GameAction", 
                     ...k,
                     dir: this.getMouseDirs(),
+                    seq: this.input_seq,
                 }
             );
-            if (this.connection.readyState === WebSocket.OPEN) {
+            if (this.connection.readyState === WebSocket.OPEN && this.phase === "Joined") {
                 this.connection.send(s);
             }
 
-            let o = translate(0, 0);
-            // render_border(ctx, t_origin.x, t_origin.y);
-            render_border(ctx, o.x, o.y);
             render_background(ctx, original_game_state.my_coords.x, original_game_state.my_coords.y);
+            if (this.map !== undefined) {
+                render_map(ctx, this.map, translate);
+            }
+
+            // Carried flags are drawn on their carrier
+            for (const flag of this.flags) {
+                if (flag.carrier === null) {
+                    const coords = translate(flag.coords.x, flag.coords.y);
+                    render_flag(ctx, coords.x, coords.y, this.teamColor(flag.team));
+                }
+            }
 
             for (let i = 0; i < t_game_state.bullets.length; i++) {
                 const bullet = t_game_state.bullets[i];
-                render_bullet(ctx, bullet.x, bullet.y, 'grey');
+                render_bullet(ctx, bullet.x, bullet.y, bullet.color);
             }
 
-            render_sprite(ctx, t_game_state.my_coords.x, t_game_state.my_coords.y, this.getMouseDirs(), this.name, 'red', t_game_state.health);
+            const me = this.entities.get(original_game_state.my_id) || {color: 'red'};
+            if (t_game_state.protected) {
+                render_shield(ctx, t_game_state.my_coords.x, t_game_state.my_coords.y);
+            }
+            render_sprite(ctx, t_game_state.my_coords.x, t_game_state.my_coords.y, this.getMouseDirs(), this.name, this.teamColor(original_game_state.team) || me.color, t_game_state.health);
+            if (original_game_state.flag) {
+                render_flag(ctx, t_game_state.my_coords.x, t_game_state.my_coords.y, this.teamColor(original_game_state.flag));
+            }
             for (let i = 0; i < t_game_state.enemies.length; i++) {
                 const enemy = t_game_state.enemies[i];
-                render_sprite(ctx, enemy.coords.x, enemy.coords.y, enemy.dir, enemy.username, 'blue', enemy.health);
+                if (enemy.protected) {
+                    render_shield(ctx, enemy.coords.x, enemy.coords.y);
+                }
+                render_sprite(ctx, enemy.coords.x, enemy.coords.y, enemy.dir, enemy.username, enemy.color, enemy.health);
+                if (enemy.flag) {
+                    render_flag(ctx, enemy.coords.x, enemy.coords.y, this.teamColor(enemy.flag));
+                }
+            }
+
+            if (this.team_scores.length > 0) {
+                render_team_scores(ctx, this.team_scores.map((s) => ({...s, color: this.teamColor(s.team)})), canvas.width / 2, 30);
+            }
+
+            const now = performance.now();
+            this.kill_feed = this.kill_feed.filter((entry) => now - entry.time < 5000);
+            render_kill_feed(ctx, this.kill_feed.map((entry) => entry.text), canvas.width - 20, 30);
+
+            this.damage_numbers = this.damage_numbers.filter((hit) => now - hit.time < 1000);
+            for (const hit of this.damage_numbers) {
+                let coords = t_game_state.my_coords;
+                if (hit.target !== original_game_state.my_id) {
+                    const enemy = original_game_state.enemies.find((enemy) => enemy.id === hit.target);
+                    if (enemy === undefined) {
+                        continue;
+                    }
+                    coords = translate(enemy.coords.x, enemy.coords.y);
+                }
+                render_damage_number(ctx, coords.x, coords.y, Math.round(hit.damage), (now - hit.time) / 1000);
             }
 
             requestAnimationFrame(loop);
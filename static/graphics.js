@@ -52,11 +52,60 @@ export function render_bullet(ctx, x, y, color) {
     ctx.restore();
 }
 
-export function render_border(ctx, origin_x, origin_y) {
-    // Render boundary
-    let width = get_settings().arena_width;
-    let height = get_settings().arena_height;
-    ctx.strokeRect(origin_x, origin_y, width, height);
+// Traces a map shape (see src/map.rs) in screen coordinates, translate maps arena coordinates to the screen
+function trace_shape(ctx, shape, translate) {
+    ctx.beginPath();
+    switch (shape.type) {
+        case "Cuboid": {
+            const c = translate(shape.center.x, shape.center.y);
+            ctx.save();
+            ctx.translate(c.x, c.y);
+            ctx.rotate(shape.rotation);
+            ctx.rect(-shape.half_width, -shape.half_height, 2 * shape.half_width, 2 * shape.half_height);
+            ctx.restore();
+            break;
+        }
+        case "Circle": {
+            const c = translate(shape.center.x, shape.center.y);
+            ctx.arc(c.x, c.y, shape.radius, 0, 2 * Math.PI);
+            break;
+        }
+        case "Polygon":
+            trace_outline(ctx, shape.points, translate);
+            break;
+    }
+}
+
+function trace_outline(ctx, points, translate) {
+    points.forEach((p, i) => {
+        const t = translate(p.x, p.y);
+        if (i === 0) ctx.moveTo(t.x, t.y);
+        else ctx.lineTo(t.x, t.y);
+    });
+    ctx.closePath();
+}
+
+export function render_map(ctx, map, translate) {
+    ctx.save();
+
+    ctx.fillStyle = 'rgba(255, 200, 0, 0.08)';
+    for (const zone of map.zones) {
+        trace_shape(ctx, zone.shape, translate);
+        ctx.fill();
+    }
+
+    ctx.beginPath();
+    trace_outline(ctx, map.boundary, translate);
+    ctx.stroke();
+
+    ctx.fillStyle = 'rgb(120, 120, 120)';
+    for (const obstacle of map.obstacles) {
+        trace_shape(ctx, obstacle, translate);
+        ctx.fill();
+        ctx.stroke();
+    }
+
+    ctx.restore();
 }
 
 export function render_background(ctx, x, y) {
@@ -94,3 +143,68 @@ export function translator(my_x, my_y, center_x, center_y) {
         return { x: x + d_x, y: y + d_y};
     };
 }
+
+export function render_kill_feed(ctx, lines, x, y) {
+    ctx.save();
+    ctx.textAlign = "right";
+    ctx.font = "16px sans-serif";
+    for (let i = 0; i < lines.length; i++) {
+        ctx.fillText(lines[i], x, y + i * 20);
+    }
+    ctx.restore();
+}
+
+// age goes from 0 to 1 over the lifetime of the number, which floats up and fades out
+export function render_damage_number(ctx, x, y, damage, age) {
+    ctx.save();
+    ctx.textAlign = "center";
+    ctx.font = "bold 16px sans-serif";
+    ctx.fillStyle = `rgba(200, 0, 0, ${1 - age})`;
+    ctx.fillText(damage, x, y - 30 - age * 30);
+    ctx.restore();
+}
+
+// Score of every team, side by side and centered on x
+export function render_team_scores(ctx, scores, x, y) {
+    ctx.save();
+    ctx.textAlign = "center";
+    ctx.font = "bold 24px sans-serif";
+    const spacing = 60;
+    const left = x - (scores.length - 1) * spacing / 2;
+    for (let i = 0; i < scores.length; i++) {
+        ctx.fillStyle = scores[i].color;
+        ctx.fillText(scores[i].score, left + i * spacing, y);
+    }
+    ctx.restore();
+}
+
+// Flag planted at x, y, pole bottom first
+export function render_flag(ctx, x, y, color) {
+    ctx.save();
+    ctx.strokeStyle = 'black';
+    ctx.lineWidth = 3;
+    ctx.beginPath();
+    ctx.moveTo(x, y);
+    ctx.lineTo(x, y - 50);
+    ctx.stroke();
+    ctx.fillStyle = color;
+    ctx.beginPath();
+    ctx.moveTo(x, y - 50);
+    ctx.lineTo(x + 30, y - 40);
+    ctx.lineTo(x, y - 30);
+    ctx.closePath();
+    ctx.fill();
+    ctx.restore();
+}
+
+// Bubble around a player with spawn protection
+export function render_shield(ctx, x, y) {
+    ctx.save();
+    ctx.beginPath();
+    ctx.arc(x, y, get_settings().ball_size * 1.8, 0, 2 * Math.PI);
+    ctx.fillStyle = 'rgba(80, 160, 255, 0.2)';
+    ctx.strokeStyle = 'rgba(80, 160, 255, 0.8)';
+    ctx.fill();
+    ctx.stroke();
+    ctx.restore();
+}
@@ -0,0 +1,114 @@
+// Decoder for the binary snapshot format described in src/protocol.rs
+
+// Must be supported by the server, see PROTOCOL_VERSION in src/protocol.rs
+export const PROTOCOL_VERSION = 16;
+
+const KEYFRAME = 0;
+const DELTA = 1;
+
+const ENEMY_COORDS = 1 << 0;
+const ENEMY_DIR = 1 << 1;
+const ENEMY_HEALTH = 1 << 2;
+const ENEMY_PROTECTED = 1 << 3;
+const ENEMY_TEAM = 1 << 4;
+const ENEMY_FLAG = 1 << 5;
+
+class Reader {
+    constructor(buffer) {
+        this.view = new DataView(buffer);
+        this.offset = 0;
+    }
+
+    u8() {
+        const v = this.view.getUint8(this.offset);
+        this.offset += 1;
+        return v;
+    }
+
+    u16() {
+        const v = this.view.getUint16(this.offset, true);
+        this.offset += 2;
+        return v;
+    }
+
+    u32() {
+        const v = this.view.getUint32(this.offset, true);
+        this.offset += 4;
+        return v;
+    }
+
+    f32() {
+        const v = this.view.getFloat32(this.offset, true);
+        this.offset += 4;
+        return v;
+    }
+}
+
+// 0 on the wire, null in JSON snapshots
+function teamOf(team) {
+    return team === 0 ? null : team;
+}
+
+// Returns the baseline seq a snapshot depends on, or null for keyframes
+export function baselineOf(buffer) {
+    const r = new Reader(buffer);
+    if (r.u8() === KEYFRAME) {
+        return null;
+    }
+    r.u32();
+    return r.u32();
+}
+
+// Decodes a snapshot, applying it on top of baseline if it is a delta
+// enemies and bullets are returned as Maps keyed on entity id
+export function decodeSnapshot(buffer, baseline) {
+    const r = new Reader(buffer);
+    const kind = r.u8();
+    const seq = r.u32();
+    let enemies = new Map();
+    let bullets = new Map();
+    if (kind === DELTA) {
+        r.u32();
+        for (const [id, enemy] of baseline.enemies) {
+            enemies.set(id, {...enemy});
+        }
+        bullets = new Map(baseline.bullets);
+    }
+
+    const timestamp = r.u32();
+    const my_id = r.u32();
+    const rtt_ms = r.u16();
+    const my_coords = {x: r.f32(), y: r.f32()};
+    const health = r.f32();
+    const last_input_seq = r.u32();
+    const my_velocity = {x: r.f32(), y: r.f32()};
+    const is_protected = r.u8() === 1;
+    const team = teamOf(r.u8());
+    const flag = teamOf(r.u8());
+
+    for (let n = r.u16(); n > 0; n--) {
+        const id = r.u32();
+        const mask = r.u8();
+        const enemy = enemies.get(id) || {id};
+        if (mask & ENEMY_COORDS) enemy.coords = {x: r.f32(), y: r.f32()};
+        if (mask & ENEMY_DIR) enemy.dir = r.f32();
+        if (mask & ENEMY_HEALTH) enemy.health = r.f32();
+        if (mask & ENEMY_PROTECTED) enemy.protected = r.u8() === 1;
+        if (mask & ENEMY_TEAM) enemy.team = teamOf(r.u8());
+        if (mask & ENEMY_FLAG) enemy.flag = teamOf(r.u8());
+        enemies.set(id, enemy);
+    }
+    for (let n = r.u16(); n > 0; n--) {
+        enemies.delete(r.u32());
+    }
+
+    for (let n = r.u16(); n > 0; n--) {
+        const id = r.u32();
+        bullets.set(id, {id, x: r.f32(), y: r.f32()});
+    }
+    for (let n = r.u16(); n > 0; n--) {
+        bullets.delete(r.u32());
+    }
+
+    return {seq, timestamp, rtt_ms, my_id, my_coords, my_velocity, last_input_seq, health, protected: is_protected, team, flag, enemies, bullets};
+}
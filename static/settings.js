@@ -6,3 +6,8 @@ await $.getJSON( "./settings.json", function( data ) {
 export function get_settings() {
     return SETTINGS;
 }
+
+// The server sends its settings in the Welcome message, which take precedence over settings.json
+export function set_settings(settings) {
+    SETTINGS = settings;
+}